@@ -0,0 +1,62 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Extension point for thermometers which need a different authentication handshake to the one
+//! implemented by `BBQDevice::authenticate`.
+
+use bluez_async::{uuid_from_u16, BluetoothSession, DeviceId};
+use cloudbbq::BBQDevice;
+use eyre::Report;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::fmt::Debug;
+
+/// The service which all compatible thermometers provide.
+pub const BBQ_SERVICE_UUID: u16 = 0xFFF0;
+const ACCOUNT_AND_VERIFY_UUID: u16 = 0xFFF2;
+
+/// A custom way to authenticate with a thermometer after connecting to it.
+///
+/// `Bbq::connect_with_authenticator` tries this first, and if it fails falls back to the default
+/// `BBQDevice::authenticate`.
+pub trait Authenticator: Debug + Send + Sync {
+    /// Authenticate with the given device, which has just been connected to. Returning an error
+    /// makes the caller try the default handshake instead.
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a BluetoothSession,
+        device_id: &'a DeviceId,
+        device: &'a BBQDevice,
+    ) -> BoxFuture<'a, Result<(), Report>>;
+}
+
+/// Authenticates by writing a pre-shared credential to the account and verify characteristic, in
+/// place of the one which `BBQDevice::authenticate` sends.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CredentialAuthenticator {
+    pub credential: Vec<u8>,
+}
+
+impl Authenticator for CredentialAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a BluetoothSession,
+        device_id: &'a DeviceId,
+        _device: &'a BBQDevice,
+    ) -> BoxFuture<'a, Result<(), Report>> {
+        async move {
+            let service = session
+                .get_service_by_uuid(device_id, uuid_from_u16(BBQ_SERVICE_UUID))
+                .await?;
+            let characteristic = session
+                .get_characteristic_by_uuid(&service.id, uuid_from_u16(ACCOUNT_AND_VERIFY_UUID))
+                .await?;
+            session
+                .write_characteristic_value(&characteristic.id, self.credential.clone())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
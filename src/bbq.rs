@@ -2,451 +2,4746 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
-use crate::config::{get_mqtt_options, Config, DeviceConfig};
-use bluez_async::{BluetoothSession, DeviceInfo, MacAddress};
+use crate::auth::{Authenticator, CredentialAuthenticator};
+use crate::bridge::{disconnect_homie, BridgeCommand};
+use crate::config::{
+    get_mqtt_options, get_tls_client_config, BatteryCurvePoint, BbqConfig, Config, DeviceConfig,
+    PresetMode, ProbeAlarmConfig, TargetComparison, TargetPreset, UnsetTargetValue,
+};
+use crate::device::Thermometer;
+use crate::homeassistant::HomeAssistantDiscovery;
+use crate::metrics::Metrics;
+use crate::notify::{AlarmReason, Notification};
+use crate::persist::{load_state, save_state};
+use crate::push::Reading;
+use crate::simulate::SimulatedDevice;
+use crate::units::{difference_from_celsius, from_celsius, to_celsius};
+use backoff::backoff::Backoff;
+use backoff::future::retry;
+use backoff::ExponentialBackoffBuilder;
+use bluez_async::{BluetoothSession, DeviceId, DeviceInfo, MacAddress};
 use cloudbbq::{BBQDevice, RealTimeData, SettingResult, TemperatureUnit};
 use eyre::{bail, Report, WrapErr};
-use futures::stream::StreamExt;
+use futures::future::{BoxFuture, Fuse};
+use futures::stream::{self, BoxStream, StreamExt};
 use futures::{select, FutureExt};
-use homie_device::{HomieDevice, Node, Property};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use homie_device::{HomieDevice, Node, Property, SpawnError};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
 use rustls::ClientConfig;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Debug, Display, Formatter};
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, timeout};
 
 const NODE_ID_BATTERY: &str = "battery";
 const PROPERTY_ID_VOLTAGE: &str = "voltage";
 const PROPERTY_ID_PERCENTAGE: &str = "percentage";
+const PROPERTY_ID_LOW: &str = "low";
 
 const NODE_ID_SETTINGS: &str = "settings";
 const PROPERTY_ID_DISPLAY_UNIT: &str = "unit";
 const PROPERTY_ID_ALARM: &str = "alarm";
+const PROPERTY_ID_ALARM_TONE: &str = "alarm_tone";
+const PROPERTY_ID_COOK_TIMEOUT: &str = "cook_timeout";
+const PROPERTY_ID_REPUBLISH: &str = "republish";
 const DISPLAY_UNIT_CELCIUS: &str = "ºC";
 const DISPLAY_UNIT_FAHRENHEIT: &str = "ºF";
-const DISPLAY_UNITS: [&str; 2] = [DISPLAY_UNIT_CELCIUS, DISPLAY_UNIT_FAHRENHEIT];
+pub const DISPLAY_UNITS: [&str; 2] = [DISPLAY_UNIT_CELCIUS, DISPLAY_UNIT_FAHRENHEIT];
+
+const NODE_ID_STATUS: &str = "status";
+const PROPERTY_ID_SUMMARY: &str = "summary";
+
+const NODE_ID_INFO: &str = "info";
+const PROPERTY_ID_CAPABILITIES: &str = "capabilities";
+const PROPERTY_ID_SELF_TEST: &str = "self_test";
+const PROPERTY_ID_MAC_ADDRESS: &str = "mac";
+const PROPERTY_ID_BLUETOOTH_NAME: &str = "bluetooth_name";
+const PROPERTY_ID_BLUETOOTH_STATUS: &str = "bluetooth_status";
+const BLUETOOTH_CONNECTED: &str = "connected";
+const BLUETOOTH_DISCONNECTED: &str = "disconnected";
+const BLUETOOTH_STATUSES: [&str; 2] = [BLUETOOTH_CONNECTED, BLUETOOTH_DISCONNECTED];
+/// How long to wait for data during the self-test before reporting it as failed.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The target temperature which the self-test sets and then clears on the first probe.
+const SELF_TEST_TARGET: f32 = 100.0;
+
+const NODE_ID_DIAGNOSTICS: &str = "diagnostics";
+const PROPERTY_ID_PACKET_RATE: &str = "packet_rate";
+/// The window over which the real-time data packet rate is averaged.
+const PACKET_RATE_WINDOW: Duration = Duration::from_secs(60);
+/// How often to publish the packet rate.
+const PACKET_RATE_INTERVAL: Duration = Duration::from_secs(10);
+const PROPERTY_ID_PUBLISH_ERRORS: &str = "publish_errors";
+/// How often to publish the count of publish errors.
+const PUBLISH_ERRORS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for the MQTT broker to acknowledge a subscription when reading a retained value.
+const RETAINED_SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a retained value after subscribing, before deciding there isn't one.
+const RETAINED_VALUE_TIMEOUT: Duration = Duration::from_secs(1);
+/// The delay before the first attempt to reconnect to the MQTT broker after the connection fails.
+const HOMIE_RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// The longest delay between attempts to reconnect to the MQTT broker.
+const HOMIE_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+const NODE_ID_TIMER: &str = "timer";
+const PROPERTY_ID_TIMER_RUNNING: &str = "running";
+const PROPERTY_ID_TIMER_ELAPSED: &str = "elapsed";
+
+const NODE_ID_AVERAGE: &str = "average";
 
 const NODE_ID_PROBE_PREFIX: &str = "probe";
 const PROPERTY_ID_TEMPERATURE: &str = "temperature";
+const PROPERTY_ID_TEMPERATURE_CELSIUS: &str = "temperature_c";
+const PROPERTY_ID_TEMPERATURE_FAHRENHEIT: &str = "temperature_f";
 const PROPERTY_ID_TARGET_TEMPERATURE_MIN: &str = "target_min";
 const PROPERTY_ID_TARGET_TEMPERATURE_MAX: &str = "target_max";
 const PROPERTY_ID_TARGET_MODE: &str = "mode";
+/// Sets the mode and temperatures of the target together, from a JSON object.
+const PROPERTY_ID_TARGET_COMMAND: &str = "set_target";
+const PROPERTY_ID_SETPOINT: &str = "setpoint";
+const PROPERTY_ID_TARGET_PRESET: &str = "preset";
+const PROPERTY_ID_TARGET_REACHED: &str = "target_reached";
+const PROPERTY_ID_PORT: &str = "port";
+const PROPERTY_ID_RECORDED_MIN: &str = "recorded_min";
+const PROPERTY_ID_RECORDED_MAX: &str = "recorded_max";
+const PROPERTY_ID_RESET_RECORDED: &str = "reset_recorded";
+const PROPERTY_ID_RATE: &str = "rate";
+const PROPERTY_ID_ETA: &str = "eta";
+/// The value published for the ETA of a probe when it can't be estimated, because there is no
+/// target or the temperature isn't rising towards it.
+const ETA_UNKNOWN: i64 = -1;
+/// How much weight to give each new rate of change measurement, to smooth out noise between
+/// readings.
+const RATE_SMOOTHING: f32 = 0.2;
+/// The slowest rate of change in ºC per minute which counts as the temperature rising, for
+/// estimating the time to reach a target. The smoothed rate only decays towards zero while the
+/// temperature is flat, so anything slower is treated as not rising at all.
+const MIN_RISING_RATE: f32 = 0.01;
+const PROPERTY_ID_ALARM_CONDITION: &str = "alarm_condition";
+const ALARM_CONDITION_NORMAL: &str = "Normal";
+const ALARM_CONDITION_LOW: &str = "Low";
+const ALARM_CONDITION_HIGH: &str = "High";
+const ALARM_CONDITIONS: [&str; 3] = [
+    ALARM_CONDITION_NORMAL,
+    ALARM_CONDITION_LOW,
+    ALARM_CONDITION_HIGH,
+];
 const TARGET_MODE_NONE: &str = "None";
 const TARGET_MODE_SINGLE: &str = "Maximum only";
 const TARGET_MODE_RANGE: &str = "Range";
-const TARGET_MODES: [&str; 3] = [TARGET_MODE_NONE, TARGET_MODE_SINGLE, TARGET_MODE_RANGE];
+const TARGET_MODE_HOLD: &str = "Hold";
+const TARGET_MODES: [&str; 4] = [
+    TARGET_MODE_NONE,
+    TARGET_MODE_SINGLE,
+    TARGET_MODE_RANGE,
+    TARGET_MODE_HOLD,
+];
 
+/// A single thermometer, either a real Bluetooth device or a simulated one, which is published as a
+/// Homie device.
 #[derive(Debug)]
-pub struct Bbq {
+pub struct Bbq<D: Thermometer = BBQDevice> {
     mac_address: MacAddress,
     config: Config,
     device_config: DeviceConfig,
     name: String,
-    device: BBQDevice,
-    target_state: Arc<Mutex<TargetState>>,
+    /// The name which the device advertises over Bluetooth, if it is known.
+    bluetooth_name: Option<String>,
+    device: D,
+    target_state: Arc<AsyncMutex<TargetState>>,
+    capabilities: Arc<Mutex<Capabilities>>,
+    /// The unit which the device is currently set to display.
+    current_unit: Arc<Mutex<TemperatureUnit>>,
+    summary: Summary,
+    packet_rate: PacketRate,
+    /// The number of values which have failed to publish.
+    publish_errors: AtomicU64,
+    /// Where to send readings to be pushed to the REST endpoint, if it is enabled.
+    push_readings: Option<mpsc::Sender<Reading>>,
+    /// Where to send notifications when the device alarms, if the webhook is enabled.
+    notifications: Option<mpsc::Sender<Notification>>,
+    /// Where to record readings to serve to Prometheus, if the metrics endpoint is enabled.
+    metrics: Option<Arc<Metrics>>,
+    /// Whether each connected probe has reached its target, so that a notification is only sent
+    /// when it first does.
+    targets_reached: HashMap<u8, bool>,
+    /// The latest values published, to publish again if requested.
+    published_values: Mutex<PublishedValues>,
+    initial_data: InitialData,
+    /// Whether each connected probe is outside its alarm thresholds, so that it only alarms when
+    /// it first goes outside them.
+    alarm_conditions: HashMap<u8, AlarmCondition>,
+    /// The lowest and highest temperatures recorded by each connected probe.
+    recorded_temperatures: HashMap<u8, RecordedTemperatures>,
+    /// The latest readings from each connected probe, to average for smoothing.
+    recent_readings: HashMap<u8, VecDeque<f32>>,
+    /// How many readings in a row each connected probe has been missing from, and since when.
+    missing_readings: HashMap<u8, (u32, Instant)>,
+    /// The temperature last published for each connected probe, and when.
+    last_temperature_publishes: HashMap<u8, (f32, Instant)>,
+    /// The average temperature last published, and when.
+    last_average_publish: Option<(f32, Instant)>,
+    /// How fast the temperature of each connected probe is changing.
+    trends: HashMap<u8, TemperatureTrend>,
+    /// The connection for publishing Home Assistant discovery config, if it is enabled.
+    homeassistant: Option<HomeAssistantDiscovery>,
+    /// How to connect to the device again if the connection to it is lost, if that is possible.
+    reconnector: Option<Box<dyn Reconnect<D>>>,
+}
+
+/// A way to connect to a thermometer again after the connection to it is lost, so that the same
+/// Homie device can be kept.
+trait Reconnect<D>: Debug + Send + Sync {
+    /// Connect to the given device again and authenticate with it.
+    fn reconnect<'a>(&'a self, device: &'a D) -> BoxFuture<'a, Result<(), Report>>;
+}
+
+/// Reconnects to a Bluetooth thermometer through BlueZ, authenticating the same way as the first
+/// time.
+#[derive(Debug)]
+struct BluetoothReconnector {
+    session: BluetoothSession,
+    device_id: DeviceId,
+    authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl Reconnect<BBQDevice> for BluetoothReconnector {
+    fn reconnect<'a>(&'a self, device: &'a BBQDevice) -> BoxFuture<'a, Result<(), Report>> {
+        async move {
+            self.session.connect(&self.device_id).await?;
+            authenticate(
+                &self.session,
+                &self.device_id,
+                device,
+                self.authenticator.as_deref(),
+            )
+            .await
+        }
+        .boxed()
+    }
 }
 
-impl Bbq {
+impl Bbq<BBQDevice> {
     /// Attempt to connect to the given Barbecue thermometer device and authenticate with it.
+    ///
+    /// If the device has an `auth_credential` configured then that is used to authenticate,
+    /// falling back to the default credential if it fails.
     pub async fn connect(
         session: &BluetoothSession,
         device: DeviceInfo,
         config: Config,
     ) -> Result<Bbq, Report> {
-        log::info!("Connecting to {:?}...", device);
-        session.connect(&device.id).await?;
-        let connected_device = BBQDevice::new(session.clone(), device.id).await?;
-        log::info!("Authenticating...");
-        connected_device.authenticate().await?;
-        log::info!("Authenticated.");
-
-        let device_config = config
-            .devices
-            .get(&device.mac_address)
-            .cloned()
-            .unwrap_or_default();
-        // Use the configured name if there is one, otherwise the Bluetooth device name.
-        let bluetooth_device_name = device.name.unwrap();
-        let name = device_config.name.clone().unwrap_or(bluetooth_device_name);
-        Ok(Bbq {
-            mac_address: device.mac_address,
+        let authenticator = config
+            .device_config(&device.mac_address)
+            .and_then(|device_config| device_config.auth_credential.clone())
+            .map(|credential| Arc::new(CredentialAuthenticator { credential }) as _);
+        Self::connect_with_authenticator(session, device, config, authenticator).await
+    }
+
+    /// Attempt to connect to the given Barbecue thermometer device and authenticate with it, trying
+    /// the given custom authenticator before the default one.
+    ///
+    /// Connecting and authenticating is retried with exponential backoff, up to the configured
+    /// number of attempts. The authenticator is kept to authenticate again if the connection is
+    /// lost and `Bbq::run` reconnects.
+    pub async fn connect_with_authenticator(
+        session: &BluetoothSession,
+        device: DeviceInfo,
+        config: Config,
+        authenticator: Option<Arc<dyn Authenticator>>,
+    ) -> Result<Bbq, Report> {
+        let max_attempts = config.bbq.connect_attempts.max(1);
+        let backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(config.bbq.connect_retry_delay_seconds))
+            .with_max_elapsed_time(None)
+            .build();
+        let mut attempt = 0;
+        let connected_device = retry(backoff, || {
+            attempt += 1;
+            let attempt = attempt;
+            let device = &device;
+            let authenticator = authenticator.as_deref();
+            async move {
+                log::info!(
+                    "Connecting to {:?} (attempt {} of {})...",
+                    device,
+                    attempt,
+                    max_attempts
+                );
+                match connect_and_authenticate(session, device, authenticator).await {
+                    Ok(connected_device) => Ok(connected_device),
+                    Err(e) if attempt < max_attempts => {
+                        log::info!(
+                            "Failed to connect to {} on attempt {}, retrying: {:?}",
+                            device.mac_address,
+                            attempt,
+                            e
+                        );
+                        Err(backoff::Error::transient(e))
+                    }
+                    Err(e) => Err(backoff::Error::permanent(e)),
+                }
+            }
+        })
+        .await?;
+
+        let device_config = config.device_config_or_default(&device.mac_address);
+        let name = device_name(
+            device_config.name.clone(),
+            device.name.clone(),
+            device.mac_address,
+        );
+        let reconnector = BluetoothReconnector {
+            session: session.clone(),
+            device_id: device.id.clone(),
+            authenticator,
+        };
+        let mut bbq = Bbq::new(
+            connected_device,
+            device.mac_address,
+            name,
+            device.name,
+            device_config,
+            config,
+        );
+        bbq.reconnector = Some(Box::new(reconnector));
+        Ok(bbq)
+    }
+}
+
+impl Bbq<SimulatedDevice> {
+    /// Create a simulated thermometer with the given index, which generates fake data.
+    pub fn simulated(index: u8, config: Config) -> Self {
+        // Use a locally administered MAC address, so it won't clash with any real device.
+        let mac_address = MacAddress::from([0x02, 0, 0, 0, 0, index]);
+        let device_config = config.device_config_or_default(&mac_address);
+        let name = device_config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Simulated BBQ {}", index));
+        let device = SimulatedDevice::new(config.simulate.clone());
+        Bbq::new(device, mac_address, name, None, device_config, config)
+    }
+}
+
+impl<D: Thermometer> Bbq<D> {
+    /// Wrap the given connected thermometer, loading any saved target state for it.
+    fn new(
+        device: D,
+        mac_address: MacAddress,
+        name: String,
+        bluetooth_name: Option<String>,
+        device_config: DeviceConfig,
+        config: Config,
+    ) -> Self {
+        let target_state = TargetState::load(&config, mac_address);
+        Bbq {
+            mac_address,
             config,
             device_config,
             name,
-            device: connected_device,
-            target_state: Arc::new(Mutex::new(TargetState::default())),
-        })
+            bluetooth_name,
+            device,
+            target_state: Arc::new(AsyncMutex::new(target_state)),
+            capabilities: Arc::new(Mutex::new(Capabilities::default())),
+            current_unit: Arc::new(Mutex::new(TemperatureUnit::Celcius)),
+            summary: Summary::default(),
+            packet_rate: PacketRate::default(),
+            publish_errors: AtomicU64::new(0),
+            push_readings: None,
+            notifications: None,
+            metrics: None,
+            targets_reached: HashMap::new(),
+            published_values: Mutex::new(PublishedValues::default()),
+            initial_data: InitialData::default(),
+            alarm_conditions: HashMap::new(),
+            recorded_temperatures: HashMap::new(),
+            recent_readings: HashMap::new(),
+            missing_readings: HashMap::new(),
+            last_temperature_publishes: HashMap::new(),
+            last_average_publish: None,
+            trends: HashMap::new(),
+            homeassistant: None,
+            reconnector: None,
+        }
     }
 
-    /// Create a Homie device for the Barbecue thermometer, and keep publishing updates.
-    pub async fn run(self, tls_client_config: Option<Arc<ClientConfig>>) -> Result<(), Report> {
+    /// Create a Homie device for the Barbecue thermometer, and keep publishing updates. Commands
+    /// received from the bridge device on `bridge_commands` are applied to the thermometer, and
+    /// readings are sent to `push_readings` and `metrics` and alarms to `notifications` if they are
+    /// given. If `once` is set then this returns after publishing the first complete set of
+    /// readings and battery level, rather than running until shutdown.
+    ///
+    /// If the data from a connected device stops then this tries reconnecting to it, keeping the
+    /// Homie device in the alert state meanwhile, and only returns an error if that fails.
+    pub async fn run(
+        mut self,
+        tls_client_config: Option<Arc<ClientConfig>>,
+        bridge_commands: Receiver<BridgeCommand>,
+        push_readings: Option<mpsc::Sender<Reading>>,
+        notifications: Option<mpsc::Sender<Notification>>,
+        metrics: Option<Arc<Metrics>>,
+        once: bool,
+    ) -> Result<(), Report> {
+        self.push_readings = push_readings;
+        self.notifications = notifications;
+        // The device is marked as disconnected in the metrics when this is dropped, however this
+        // returns.
+        let _metrics_connected = metrics
+            .as_ref()
+            .map(|metrics| metrics.connected(self.mac_address, &self.name));
+        self.metrics = metrics;
         let device_id_suffix = self.mac_address.to_string().replace(':', "");
-        let device_base = format!(
-            "{}/{}-{}",
-            self.config.homie.prefix, self.config.homie.device_id_prefix, device_id_suffix
-        );
+        let device_base = self.config.homie.device_base(&device_id_suffix, &self.name);
+        // A device-specific MQTT config replaces the global one, including its TLS settings.
+        let (mqtt_config, tls_client_config) = if let Some(mqtt_config) = &self.device_config.mqtt {
+            let tls_client_config = get_tls_client_config(mqtt_config).wrap_err_with(|| {
+                format!(
+                    "Failed to set up TLS for MQTT config of device {}",
+                    self.name
+                )
+            })?;
+            (mqtt_config, tls_client_config)
+        } else {
+            (&self.config.mqtt, tls_client_config)
+        };
         let mqtt_options =
-            get_mqtt_options(&self.config.mqtt, &device_id_suffix, tls_client_config);
-        let mut homie_builder = HomieDevice::builder(&device_base, &self.name, mqtt_options);
-        homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-        let device_clone = self.device.clone();
-        let target_state = self.target_state.clone();
-        homie_builder.set_update_callback(move |node_id, property_id, value| {
-            let device_clone = device_clone.clone();
-            let target_state = target_state.clone();
-            async {
-                Self::handle_update(device_clone, target_state, node_id, property_id, value).await
+            get_mqtt_options(mqtt_config, &device_id_suffix, tls_client_config.clone());
+        // This needs a separate client, as the Homie device publishes its own default values as
+        // soon as it is spawned.
+        let restored_unit = if self.config.bbq.restore_display_unit {
+            let restore_options = get_mqtt_options(
+                mqtt_config,
+                &format!("{}-restore", device_id_suffix),
+                tls_client_config.clone(),
+            );
+            let topic = format!(
+                "{}/{}/{}",
+                device_base, NODE_ID_SETTINGS, PROPERTY_ID_DISPLAY_UNIT
+            );
+            match read_retained(restore_options, &topic).await {
+                Ok(Some(value)) => {
+                    let unit = parse_display_unit(&value);
+                    if unit.is_none() {
+                        log::warn!(
+                            "Ignoring invalid retained unit {:?} for {}",
+                            value,
+                            self.name
+                        );
+                    }
+                    unit
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!("Failed to read retained unit for {}: {}", self.name, e);
+                    None
+                }
             }
-        });
-        let (mut homie, homie_handle) = homie_builder.spawn().await?;
-        homie.ready().await?;
-
-        // Add nodes other than probes.
-        homie
-            .add_node(Node::new(
-                NODE_ID_BATTERY,
-                "Battery",
-                "Battery level",
-                vec![
-                    Property::integer(PROPERTY_ID_VOLTAGE, "Voltage", false, true, None, None),
-                    Property::integer(
-                        PROPERTY_ID_PERCENTAGE,
-                        "Percentage",
-                        false,
-                        true,
-                        Some("%"),
-                        None,
-                    ),
-                ],
-            ))
-            .await?;
-        homie
-            .add_node(Node::new(
-                NODE_ID_SETTINGS,
-                "Settings",
-                "Settings",
-                vec![
-                    Property::enumeration(
-                        PROPERTY_ID_DISPLAY_UNIT,
-                        "Unit",
-                        true,
-                        true,
-                        None,
-                        &DISPLAY_UNITS,
-                    ),
-                    Property::boolean(PROPERTY_ID_ALARM, "Alarm", true, false, None),
-                ],
-            ))
-            .await?;
-        // Default to Celcius.
-        self.device
-            .set_temperature_unit(TemperatureUnit::Celcius)
-            .await?;
-        homie
-            .publish_value(
-                NODE_ID_SETTINGS,
-                PROPERTY_ID_DISPLAY_UNIT,
-                DISPLAY_UNIT_CELCIUS,
-            )
+        } else {
+            None
+        };
+        if self.config.homie.homeassistant_discovery {
+            self.homeassistant = Some(HomeAssistantDiscovery::connect(
+                get_mqtt_options(
+                    mqtt_config,
+                    &format!("{}-homeassistant", device_id_suffix),
+                    tls_client_config,
+                ),
+                mqtt_config.qos(),
+                &self.config.homie.homeassistant_prefix,
+                &format!(
+                    "{}-{}",
+                    self.config.homie.device_id_prefix, device_id_suffix
+                ),
+                &device_base,
+                &self.name,
+            ));
+        }
+        let (target_updates_tx, target_updates_rx) = unbounded();
+        let (republish_requests_tx, republish_requests_rx) = unbounded();
+        let (unit_updates_tx, unit_updates_rx) = unbounded();
+        let (recorded_resets_tx, recorded_resets_rx) = unbounded();
+        let (timer_commands_tx, timer_commands_rx) = unbounded();
+        let update_handler = UpdateHandler {
+            device: self.device.clone(),
+            target_state: self.target_state.clone(),
+            capabilities: self.capabilities.clone(),
+            current_unit: self.current_unit.clone(),
+            target_updates: target_updates_tx,
+            republish_requests: republish_requests_tx,
+            recorded_resets: recorded_resets_tx,
+            timer_commands: timer_commands_tx,
+            unit_updates: self
+                .config
+                .bbq
+                .publish_display_unit
+                .then_some(unit_updates_tx),
+            probe_offsets: self.device_config.probe_offsets.clone(),
+            resync_targets_on_unit_change: self.config.bbq.resync_targets_on_unit_change,
+            state_file: self
+                .config
+                .bbq
+                .state_file
+                .clone()
+                .map(|state_file| (state_file, self.mac_address)),
+            target_presets: self.config.bbq.target_presets.clone(),
+        };
+        let (mut homie, homie_handle) = self
+            .spawn_homie(&device_base, mqtt_options.clone(), &update_handler)
             .await?;
+        // Either mark the device as ready now, or once the first real-time data and battery level
+        // have been published.
+        let mut ready = !self.config.bbq.wait_for_initial_data;
+        if ready {
+            homie.ready().await?;
+        }
+
+        self.add_nodes(&mut homie).await?;
+        // The Homie $state only reflects the MQTT connection, so say separately that the
+        // thermometer is connected, until the data from it stops.
+        self.publish_value(
+            &homie,
+            NODE_ID_INFO,
+            PROPERTY_ID_BLUETOOTH_STATUS,
+            BLUETOOTH_CONNECTED,
+        )
+        .await;
+        let mut cook_timer = CookTimer::default();
+        self.publish_timer(&homie, &cook_timer, Instant::now())
+            .await;
+        self.capabilities.lock().unwrap().alarm_tones = self.device.alarm_tones();
+        // Use the unit which was last published if there is one, or else default to Celcius unless
+        // another unit is configured for the device or globally.
+        let default_unit = restored_unit
+            .or(self.device_config.unit)
+            .or(self.config.bbq.default_unit)
+            .unwrap_or(TemperatureUnit::Celcius);
+        let unit_result = self.device.set_temperature_unit(default_unit).await;
+        match &unit_result {
+            Ok(()) => *self.current_unit.lock().unwrap() = default_unit,
+            Err(e) => log::warn!("Failed to set temperature unit of {}: {}", self.name, e),
+        }
+        self.capabilities.lock().unwrap().unit = Some(unit_result.is_ok());
+        let current_unit = *self.current_unit.lock().unwrap();
+        self.publish_value(
+            &homie,
+            NODE_ID_SETTINGS,
+            PROPERTY_ID_DISPLAY_UNIT,
+            display_unit(current_unit),
+        )
+        .await;
+        if !self.device_config.average_probes.is_empty() {
+            self.add_average_node(&mut homie).await?;
+        }
 
-        let mut setting_results = self.device.setting_results().await?.fuse();
-        let mut real_time_data = self.device.real_time().await?.fuse();
+        let mut setting_results = with_end(self.device.setting_results().await?);
+        let mut real_time_data = with_end(self.device.real_time().await?);
+        let mut setting_results_ended = false;
+        let mut real_time_data_ended = false;
+        // The cook is taken to start when the device is connected.
+        let mut cook_timeout =
+            Box::pin(if let Some(max_cook_duration) = self.max_cook_duration() {
+                self.publish_value(&homie, NODE_ID_SETTINGS, PROPERTY_ID_COOK_TIMEOUT, false)
+                    .await;
+                sleep(max_cook_duration).fuse()
+            } else {
+                Fuse::terminated()
+            });
         self.device.enable_real_time_data(true).await?;
         // Request an initial battery level reading.
         self.device.request_battery_level().await?;
 
         let mut homie_handle = homie_handle.fuse();
+        // If the connection to the MQTT broker fails then keep the Bluetooth connection, and try
+        // connecting a new Homie device with increasing delays until it works.
+        let mut homie_backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(HOMIE_RECONNECT_INITIAL_DELAY)
+            .with_max_interval(HOMIE_RECONNECT_MAX_DELAY)
+            .with_max_elapsed_time(None)
+            .build();
+        let mut homie_connected_at = Instant::now();
+        let mut homie_reconnect_timeout = Box::pin(Fuse::terminated());
+        // Similarly if the data from the device stops then keep the Homie device, in the alert
+        // state, while trying to reconnect to the device a limited number of times.
+        let bluetooth_reconnect_attempts = if self.reconnector.is_some() {
+            self.config.bbq.connect_attempts
+        } else {
+            0
+        };
+        let mut bluetooth_backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(
+                self.config.bbq.connect_retry_delay_seconds,
+            ))
+            .with_max_elapsed_time(None)
+            .build();
+        // The current attempt to reconnect to the device, or 0 if it is connected.
+        let mut bluetooth_reconnect_attempt = 0;
+        let mut bluetooth_reconnect_timeout = Box::pin(Fuse::terminated());
+        let mut target_updates = target_updates_rx.fuse();
+        let mut republish_requests = republish_requests_rx.fuse();
+        let mut unit_updates = unit_updates_rx.fuse();
+        let mut recorded_resets = recorded_resets_rx.fuse();
+        let mut timer_commands = timer_commands_rx.fuse();
+        let mut bridge_commands = stream::unfold(bridge_commands, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(command) => return Some((command, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+        .fuse();
+        let mut ready_timeout = Box::pin(if ready {
+            Fuse::terminated()
+        } else {
+            sleep(Duration::from_secs(
+                self.config.bbq.initial_data_timeout_seconds,
+            ))
+            .fuse()
+        });
 
-        loop {
-            select! {
-                data = real_time_data.select_next_some() => self.handle_realtime_data(data, &mut homie).await?,
-                result = setting_results.select_next_some() => self.handle_setting_result(result, &mut homie).await?,
-                homie_result = homie_handle => return homie_result.wrap_err("Homie error"),
-                complete => break,
-            };
-        }
+        // Some devices need enabling real-time data again if none arrives at first.
+        let real_time_timeout =
+            Duration::from_secs(self.config.bbq.real_time_enable_timeout_seconds);
+        let mut real_time_enable_retries = self.config.bbq.real_time_enable_retries;
+        let mut real_time_enable_timeout = Box::pin(if real_time_enable_retries > 0 {
+            sleep(real_time_timeout).fuse()
+        } else {
+            Fuse::terminated()
+        });
 
-        Ok(())
-    }
+        let battery_poll_interval =
+            Duration::from_secs(self.config.bbq.battery_poll_interval_seconds);
+        let mut battery_poll_timeout = Box::pin(if battery_poll_interval.is_zero() {
+            Fuse::terminated()
+        } else {
+            sleep(battery_poll_interval).fuse()
+        });
 
-    async fn handle_update(
-        device: BBQDevice,
-        target_state: Arc<Mutex<TargetState>>,
-        node_id: String,
-        property_id: String,
-        value: String,
-    ) -> Option<String> {
-        log::trace!("{}/{} = {}", node_id, property_id, value);
-        if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_DISPLAY_UNIT {
-            let unit = parse_display_unit(&value)?;
-            if let Err(e) = device.set_temperature_unit(unit).await {
-                log::error!("Failed to set temperature unit: {}", e);
-                return None;
-            }
-            Some(value)
-        } else if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_ALARM {
-            let state: bool = value.parse().ok()?;
-            if !state {
-                if let Err(e) = device.silence_alarm().await {
-                    log::error!("Failed to silence alarm: {}", e);
-                    return None;
+        let timer_publish_interval =
+            Duration::from_secs(self.config.bbq.timer_publish_interval_seconds.max(1));
+        let mut timer_publish_timeout = Box::pin(Fuse::terminated());
+
+        let mut publish_errors_timeout = Box::pin(if self.config.bbq.publish_error_count {
+            sleep(Duration::ZERO).fuse()
+        } else {
+            Fuse::terminated()
+        });
+
+        // Authentication must already have worked to get this far. Check that a target can be set
+        // and cleared now, and then that data arrives.
+        let mut self_test_targets = if self.config.bbq.self_test {
+            Some(self.self_test_targets().await)
+        } else {
+            None
+        };
+        let mut self_test_timeout = Box::pin(if self_test_targets.is_some() {
+            sleep(SELF_TEST_TIMEOUT).fuse()
+        } else {
+            Fuse::terminated()
+        });
+
+        let stop = loop {
+            let mut timed_out = false;
+            let mut self_test_timed_out = false;
+            let result = select! {
+                data = real_time_data.select_next_some() => match data {
+                    Some(data) => self.handle_realtime_data(data, &mut homie).await,
+                    None => {
+                        real_time_data_ended = true;
+                        Ok(())
+                    }
+                },
+                result = setting_results.select_next_some() => match result {
+                    Some(result) => self.handle_setting_result(result, &mut homie).await,
+                    None => {
+                        setting_results_ended = true;
+                        Ok(())
+                    }
+                },
+                (probe_index, target) = target_updates.select_next_some() => self.publish_target(&homie, probe_index, &target).await,
+                () = republish_requests.select_next_some() => self.republish(&homie).await,
+                () = unit_updates.select_next_some() => self.update_probe_units(&mut homie).await,
+                probe_index = recorded_resets.select_next_some() => {
+                    self.recorded_temperatures.remove(&probe_index);
+                    Ok(())
                 }
-                Some(value)
-            } else {
-                None
-            }
-        } else if let Some(probe_index) = probe_id_to_index(&node_id) {
-            let target = {
-                let state = &mut *target_state.lock().unwrap();
-                let target = state.target(probe_index);
-                match property_id.as_ref() {
-                    PROPERTY_ID_TARGET_TEMPERATURE_MIN => {
-                        target.temperature_min = value.parse().ok()?;
+                running = timer_commands.select_next_some() => {
+                    let now = Instant::now();
+                    if running {
+                        cook_timer.start(now);
+                        timer_publish_timeout.set(sleep(timer_publish_interval).fuse());
+                    } else {
+                        cook_timer.stop(now);
+                        timer_publish_timeout.set(Fuse::terminated());
+                    }
+                    self.publish_timer(&homie, &cook_timer, now).await;
+                    Ok(())
+                }
+                () = timer_publish_timeout => {
+                    self.publish_timer(&homie, &cook_timer, Instant::now()).await;
+                    timer_publish_timeout.set(sleep(timer_publish_interval).fuse());
+                    Ok(())
+                }
+                command = bridge_commands.select_next_some() => {
+                    if command == BridgeCommand::Shutdown {
+                        log::info!("Shutting down {}", self.name);
+                        break Stop::Shutdown;
+                    }
+                    self.handle_bridge_command(command, &homie, &update_handler).await
+                }
+                homie_result = homie_handle => {
+                    if let Err(e) = homie_result {
+                        log::warn!("Homie device for {} failed: {}", self.name, e);
+                        // Only start the delays again if the last connection lasted a while.
+                        if homie_connected_at.elapsed() >= HOMIE_RECONNECT_MAX_DELAY {
+                            homie_backoff.reset();
+                        }
+                        let delay = homie_backoff.next_backoff().unwrap_or(HOMIE_RECONNECT_MAX_DELAY);
+                        log::info!("Reconnecting {} to MQTT broker in {:?}", self.name, delay);
+                        homie_reconnect_timeout.set(sleep(delay).fuse());
+                        Ok(())
+                    } else {
+                        return Ok(());
                     }
-                    PROPERTY_ID_TARGET_TEMPERATURE_MAX => {
-                        target.temperature_max = value.parse().ok()?;
+                }
+                () = homie_reconnect_timeout => {
+                    let result = async {
+                        let (mut new_homie, new_homie_handle) = self
+                            .spawn_homie(&device_base, mqtt_options.clone(), &update_handler)
+                            .await?;
+                        self.restore_homie(&homie, &mut new_homie, ready && bluetooth_reconnect_attempt == 0)
+                            .await?;
+                        Ok::<_, Report>((new_homie, new_homie_handle))
                     }
-                    PROPERTY_ID_TARGET_MODE => {
-                        target.mode = value.parse().ok()?;
+                    .await;
+                    match result {
+                        Ok((new_homie, new_homie_handle)) => {
+                            log::info!("Reconnected {} to MQTT broker", self.name);
+                            homie = new_homie;
+                            homie_handle = new_homie_handle.fuse();
+                            homie_connected_at = Instant::now();
+                        }
+                        Err(e) => {
+                            let delay = homie_backoff.next_backoff().unwrap_or(HOMIE_RECONNECT_MAX_DELAY);
+                            log::warn!("Failed to reconnect {} to MQTT broker, trying again in {:?}: {}", self.name, delay, e);
+                            homie_reconnect_timeout.set(sleep(delay).fuse());
+                        }
                     }
-                    _ => return None,
-                };
-                target.clone()
+                    Ok(())
+                }
+                () = bluetooth_reconnect_timeout => {
+                    log::info!(
+                        "Reconnecting to {} (attempt {} of {})...",
+                        self.name,
+                        bluetooth_reconnect_attempt,
+                        bluetooth_reconnect_attempts
+                    );
+                    match self.reconnect_device().await {
+                        Ok((new_setting_results, new_real_time_data)) => {
+                            log::info!("Reconnected to {}", self.name);
+                            setting_results = with_end(new_setting_results);
+                            real_time_data = with_end(new_real_time_data);
+                            setting_results_ended = false;
+                            real_time_data_ended = false;
+                            bluetooth_reconnect_attempt = 0;
+                            self.publish_value(&homie, NODE_ID_INFO, PROPERTY_ID_BLUETOOTH_STATUS, BLUETOOTH_CONNECTED).await;
+                            if ready {
+                                if let Err(e) = homie.ready().await {
+                                    log::warn!("Failed to mark {} as ready: {}", self.name, e);
+                                }
+                            }
+                        }
+                        Err(e) if bluetooth_reconnect_attempt < bluetooth_reconnect_attempts => {
+                            bluetooth_reconnect_attempt += 1;
+                            let delay = bluetooth_backoff.next_backoff().unwrap_or_default();
+                            log::warn!("Failed to reconnect to {}, trying again in {:?}: {}", self.name, delay, e);
+                            bluetooth_reconnect_timeout.set(sleep(delay).fuse());
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to reconnect to {}, giving up: {}", self.name, e);
+                            break Stop::StreamsEnded;
+                        }
+                    }
+                    Ok(())
+                }
+                () = real_time_enable_timeout => {
+                    if self.initial_data.real_time {
+                        Ok(())
+                    } else {
+                        log::info!("No real-time data from {} yet, enabling it again", self.name);
+                        real_time_enable_retries -= 1;
+                        if real_time_enable_retries > 0 {
+                            real_time_enable_timeout.set(sleep(real_time_timeout).fuse());
+                        }
+                        if let Err(e) = self.device.enable_real_time_data(true).await {
+                            log::warn!("Failed to enable real-time data on {}: {}", self.name, e);
+                        }
+                        Ok(())
+                    }
+                }
+                () = cook_timeout => self.handle_cook_timeout(&homie).await,
+                () = battery_poll_timeout => {
+                    battery_poll_timeout.set(sleep(battery_poll_interval).fuse());
+                    // Missing one poll doesn't matter, there will be another.
+                    if let Err(e) = self.device.request_battery_level().await {
+                        log::warn!("Failed to request battery level of {}: {}", self.name, e);
+                    }
+                    Ok(())
+                }
+                () = publish_errors_timeout => {
+                    let publish_errors = self.publish_errors.load(Ordering::Relaxed);
+                    self.publish_value(&homie, NODE_ID_DIAGNOSTICS, PROPERTY_ID_PUBLISH_ERRORS, publish_errors).await;
+                    publish_errors_timeout.set(sleep(PUBLISH_ERRORS_INTERVAL).fuse());
+                    Ok(())
+                }
+                () = self_test_timeout => {
+                    self_test_timed_out = true;
+                    Ok(())
+                }
+                () = ready_timeout => {
+                    log::warn!("Timed out waiting for initial data from {}", self.name);
+                    timed_out = true;
+                    Ok(())
+                }
+                complete => break Stop::StreamsEnded,
             };
-            if let Err(e) = set_target(&device, probe_index, &target).await {
-                log::error!("Failed to set target temperature: {}", e);
-                return None;
+            if let Err(e) = result {
+                break Stop::Failed(e);
             }
-            Some(value)
-        } else {
-            None
+            if real_time_data_ended && setting_results_ended && bluetooth_reconnect_attempt == 0 {
+                if bluetooth_reconnect_attempts == 0 {
+                    break Stop::StreamsEnded;
+                }
+                log::warn!("Data from {} stopped, reconnecting", self.name);
+                self.publish_value(
+                    &homie,
+                    NODE_ID_INFO,
+                    PROPERTY_ID_BLUETOOTH_STATUS,
+                    BLUETOOTH_DISCONNECTED,
+                )
+                .await;
+                if ready {
+                    if let Err(e) = homie.alert().await {
+                        log::warn!("Failed to mark {} as in alert state: {}", self.name, e);
+                    }
+                }
+                bluetooth_backoff.reset();
+                bluetooth_reconnect_attempt = 1;
+                bluetooth_reconnect_timeout
+                    .set(sleep(bluetooth_backoff.next_backoff().unwrap_or_default()).fuse());
+            }
+            self.publish_capabilities(&homie).await?;
+            if self_test_timed_out || self.initial_data.is_complete() {
+                if let Some(targets_ok) = self_test_targets.take() {
+                    let result = self_test_result(&self.initial_data, targets_ok);
+                    if result == SELF_TEST_PASSED {
+                        log::info!("Self-test of {} passed", self.name);
+                    } else {
+                        log::error!("Self-test of {} {}", self.name, result);
+                    }
+                    self.publish_value(&homie, NODE_ID_INFO, PROPERTY_ID_SELF_TEST, result)
+                        .await;
+                }
+            }
+            if !ready
+                && bluetooth_reconnect_attempt == 0
+                && (timed_out || self.initial_data.is_complete())
+            {
+                homie.ready().await?;
+                ready = true;
+            }
+            if once && self.initial_data.is_complete() {
+                log::info!("Got readings from {}, disconnecting", self.name);
+                break Stop::Finished;
+            }
+        };
+
+        self.publish_value(
+            &homie,
+            NODE_ID_INFO,
+            PROPERTY_ID_BLUETOOTH_STATUS,
+            BLUETOOTH_DISCONNECTED,
+        )
+        .await;
+
+        if let Stop::Shutdown | Stop::Finished = stop {
+            // Stop the device sending data which nobody is listening to any more.
+            if let Err(e) = self.device.enable_real_time_data(false).await {
+                log::warn!("Failed to disable real-time data on {}: {}", self.name, e);
+            }
+            if let Some(homeassistant) = self.homeassistant.take() {
+                homeassistant.remove_all().await;
+            }
+            disconnect_homie(homie, homie_handle, &self.name).await;
+            return Ok(());
         }
-    }
 
-    async fn handle_setting_result(
-        &self,
-        result: SettingResult,
-        homie: &mut HomieDevice,
-    ) -> Result<(), Report> {
-        log::trace!("Setting result: {:?}", result);
-        match result {
-            SettingResult::BatteryLevel {
-                current_voltage,
-                max_voltage,
-            } => {
-                let percentage = current_voltage as u32 * 100 / max_voltage as u32;
-                homie
-                    .publish_value(NODE_ID_BATTERY, PROPERTY_ID_VOLTAGE, current_voltage)
-                    .await?;
-                homie
-                    .publish_value(NODE_ID_BATTERY, PROPERTY_ID_PERCENTAGE, percentage)
-                    .await?;
+        // Keep the Homie device around in the alert state for a while before giving up on it, so
+        // that a device which briefly drops out doesn't churn.
+        let grace_period = Duration::from_secs(self.config.bbq.grace_period_seconds);
+        if !grace_period.is_zero() {
+            log::info!(
+                "Device {} stopped, waiting {:?} before reconnecting",
+                self.name,
+                grace_period
+            );
+            if ready {
+                if let Err(e) = homie.alert().await {
+                    log::warn!("Failed to mark {} as in alert state: {}", self.name, e);
+                }
             }
-            SettingResult::SilencePressed => {
-                homie
-                    .publish_nonretained_value(NODE_ID_SETTINGS, PROPERTY_ID_ALARM, false)
-                    .await?;
+            let mut grace_timeout = sleep(grace_period).boxed().fuse();
+            select! {
+                homie_result = homie_handle => homie_result.wrap_err("Homie error")?,
+                () = grace_timeout => {},
             }
-            _ => {}
         }
-        Ok(())
+
+        stop.into_result(&self.name)
     }
 
-    fn node_for_probe(&self, node_id: &str, probe_index: u8) -> Node {
-        let default_probe_name = format!("Probe {}", probe_index + 1);
-        let probe_name = self
-            .device_config
-            .probe_names
-            .get(probe_index as usize)
-            .unwrap_or(&default_probe_name);
-        Node::new(
-            node_id,
-            probe_name,
-            "Temperature probe",
+    /// Connect to the device again after the data from it stopped, and start it sending data
+    /// again. Returns the new setting results and real-time data streams.
+    async fn reconnect_device(
+        &self,
+    ) -> Result<
+        (
+            BoxStream<'static, SettingResult>,
+            BoxStream<'static, RealTimeData>,
+        ),
+        Report,
+    > {
+        if let Some(reconnector) = &self.reconnector {
+            reconnector.reconnect(&self.device).await?;
+        }
+        let setting_results = self.device.setting_results().await?;
+        let real_time_data = self.device.real_time().await?;
+        self.device.enable_real_time_data(true).await?;
+        if let Err(e) = self.device.request_battery_level().await {
+            log::warn!("Failed to request battery level of {}: {}", self.name, e);
+        }
+        Ok((setting_results, real_time_data))
+    }
+
+    /// Add the nodes other than probes and the average to the Homie device, and publish the
+    /// properties which never change.
+    async fn add_nodes(&self, homie: &mut HomieDevice) -> Result<(), Report> {
+        let battery_node = Node::new(
+            NODE_ID_BATTERY,
+            self.config.node_label(NODE_ID_BATTERY, "Battery"),
+            "Battery level",
             vec![
-                Property::float(
-                    PROPERTY_ID_TEMPERATURE,
-                    "Temperature",
+                Property::integer(
+                    PROPERTY_ID_VOLTAGE,
+                    self.config
+                        .property_label(NODE_ID_BATTERY, PROPERTY_ID_VOLTAGE, "Voltage"),
                     false,
                     true,
-                    Some("ºC"),
                     None,
-                ),
-                Property::float(
-                    PROPERTY_ID_TARGET_TEMPERATURE_MIN,
-                    "Minimum temperature",
-                    true,
-                    true,
-                    Some("ºC"),
                     None,
                 ),
-                Property::float(
-                    PROPERTY_ID_TARGET_TEMPERATURE_MAX,
-                    "Target/maximum temperature",
-                    true,
+                Property::integer(
+                    PROPERTY_ID_PERCENTAGE,
+                    self.config.property_label(
+                        NODE_ID_BATTERY,
+                        PROPERTY_ID_PERCENTAGE,
+                        "Percentage",
+                    ),
+                    false,
                     true,
-                    Some("ºC"),
+                    Some("%"),
                     None,
                 ),
-                Property::enumeration(
-                    PROPERTY_ID_TARGET_MODE,
-                    "Target mode",
-                    true,
+                Property::boolean(
+                    PROPERTY_ID_LOW,
+                    self.config
+                        .property_label(NODE_ID_BATTERY, PROPERTY_ID_LOW, "Low"),
+                    false,
                     true,
                     None,
-                    &TARGET_MODES,
                 ),
             ],
+        );
+        self.publish_discovery(
+            &battery_node,
+            PROPERTY_ID_VOLTAGE,
+            Some("voltage"),
+            Some("mV"),
         )
-    }
-
-    async fn handle_realtime_data(
-        &self,
-        data: RealTimeData,
-        homie: &mut HomieDevice,
-    ) -> Result<(), Report> {
-        log::trace!("Realtime data: {:?}", data);
-        for (probe_index, temperature) in data.probe_temperatures.into_iter().enumerate() {
-            let node_id = format!("{}{}", NODE_ID_PROBE_PREFIX, probe_index);
-            let exists = homie.has_node(&node_id);
-            if let Some(temperature) = temperature {
-                if !exists {
-                    self.add_probe(homie, probe_index as u8, &node_id).await?;
-                }
-                homie
-                    .publish_value(&node_id, PROPERTY_ID_TEMPERATURE, temperature)
-                    .await?;
-            } else if exists {
-                homie.remove_node(&node_id).await?;
-            }
+        .await;
+        self.publish_discovery(
+            &battery_node,
+            PROPERTY_ID_PERCENTAGE,
+            Some("battery"),
+            Some("%"),
+        )
+        .await;
+        self.add_node(homie, battery_node).await?;
+        let mut settings_properties = vec![
+            Property::enumeration(
+                PROPERTY_ID_DISPLAY_UNIT,
+                self.config
+                    .property_label(NODE_ID_SETTINGS, PROPERTY_ID_DISPLAY_UNIT, "Unit"),
+                true,
+                true,
+                None,
+                &DISPLAY_UNITS,
+            ),
+            Property::boolean(
+                PROPERTY_ID_ALARM,
+                self.config
+                    .property_label(NODE_ID_SETTINGS, PROPERTY_ID_ALARM, "Alarm"),
+                true,
+                false,
+                None,
+            ),
+            Property::boolean(
+                PROPERTY_ID_REPUBLISH,
+                self.config.property_label(
+                    NODE_ID_SETTINGS,
+                    PROPERTY_ID_REPUBLISH,
+                    "Republish all values",
+                ),
+                true,
+                false,
+                None,
+            ),
+        ];
+        // Only offer a choice of alarm tone if the device supports it.
+        let alarm_tones = self.device.alarm_tones();
+        if !alarm_tones.is_empty() {
+            settings_properties.push(Property::enumeration(
+                PROPERTY_ID_ALARM_TONE,
+                self.config
+                    .property_label(NODE_ID_SETTINGS, PROPERTY_ID_ALARM_TONE, "Alarm tone"),
+                true,
+                true,
+                None,
+                alarm_tones,
+            ));
         }
-        Ok(())
-    }
-
-    async fn add_probe(
-        &self,
-        homie: &mut HomieDevice,
-        probe_index: u8,
-        node_id: &str,
-    ) -> Result<(), Report> {
-        homie
-            .add_node(self.node_for_probe(node_id, probe_index))
-            .await?;
-
-        // Restore the target temperature to its previous value, or none.
-        let target = self
-            .target_state
-            .lock()
-            .unwrap()
-            .target(probe_index)
-            .clone();
-        set_target(&self.device, probe_index, &target).await?;
-        homie
-            .publish_value(node_id, PROPERTY_ID_TARGET_MODE, target.mode)
-            .await?;
-        homie
-            .publish_value(
-                node_id,
-                PROPERTY_ID_TARGET_TEMPERATURE_MIN,
-                target.temperature_min,
+        if self.max_cook_duration().is_some() {
+            settings_properties.push(Property::boolean(
+                PROPERTY_ID_COOK_TIMEOUT,
+                self.config.property_label(
+                    NODE_ID_SETTINGS,
+                    PROPERTY_ID_COOK_TIMEOUT,
+                    "Maximum cook duration exceeded",
+                ),
+                false,
+                true,
+                None,
+            ));
+        }
+        let settings_node = Node::new(
+            NODE_ID_SETTINGS,
+            self.config.node_label(NODE_ID_SETTINGS, "Settings"),
+            "Settings",
+            settings_properties,
+        );
+        self.publish_discovery(&settings_node, PROPERTY_ID_DISPLAY_UNIT, None, None)
+            .await;
+        self.add_node(homie, settings_node).await?;
+        // The devices don't report their model or firmware version, so this is all there is.
+        let mut info_properties = vec![
+            Property::string(
+                PROPERTY_ID_CAPABILITIES,
+                self.config
+                    .property_label(NODE_ID_INFO, PROPERTY_ID_CAPABILITIES, "Capabilities"),
+                false,
+                true,
+                None,
+            ),
+            Property::string(
+                PROPERTY_ID_MAC_ADDRESS,
+                self.config
+                    .property_label(NODE_ID_INFO, PROPERTY_ID_MAC_ADDRESS, "MAC address"),
+                false,
+                true,
+                None,
+            ),
+            Property::string(
+                PROPERTY_ID_BLUETOOTH_NAME,
+                self.config.property_label(
+                    NODE_ID_INFO,
+                    PROPERTY_ID_BLUETOOTH_NAME,
+                    "Bluetooth name",
+                ),
+                false,
+                true,
+                None,
+            ),
+            Property::enumeration(
+                PROPERTY_ID_BLUETOOTH_STATUS,
+                self.config.property_label(
+                    NODE_ID_INFO,
+                    PROPERTY_ID_BLUETOOTH_STATUS,
+                    "Bluetooth status",
+                ),
+                false,
+                true,
+                None,
+                &BLUETOOTH_STATUSES,
+            ),
+        ];
+        if self.config.bbq.self_test {
+            info_properties.push(Property::string(
+                PROPERTY_ID_SELF_TEST,
+                self.config
+                    .property_label(NODE_ID_INFO, PROPERTY_ID_SELF_TEST, "Self-test result"),
+                false,
+                true,
+                None,
+            ));
+        }
+        self.add_node(
+            homie,
+            Node::new(
+                NODE_ID_INFO,
+                self.config.node_label(NODE_ID_INFO, "Info"),
+                "Bridge information",
+                info_properties,
+            ),
+        )
+        .await?;
+        self.publish_value(
+            homie,
+            NODE_ID_INFO,
+            PROPERTY_ID_MAC_ADDRESS,
+            self.mac_address,
+        )
+        .await;
+        self.publish_value(
+            homie,
+            NODE_ID_INFO,
+            PROPERTY_ID_BLUETOOTH_NAME,
+            self.bluetooth_name.as_deref().unwrap_or_default(),
+        )
+        .await;
+        if self.config.bbq.summary_template.is_some() {
+            self.add_node(
+                homie,
+                Node::new(
+                    NODE_ID_STATUS,
+                    self.config.node_label(NODE_ID_STATUS, "Status"),
+                    "Status",
+                    vec![Property::string(
+                        PROPERTY_ID_SUMMARY,
+                        self.config
+                            .property_label(NODE_ID_STATUS, PROPERTY_ID_SUMMARY, "Summary"),
+                        false,
+                        true,
+                        None,
+                    )],
+                ),
             )
             .await?;
-        homie
-            .publish_value(
-                node_id,
-                PROPERTY_ID_TARGET_TEMPERATURE_MAX,
-                target.temperature_max,
+        }
+        let mut diagnostics_properties = vec![];
+        if self.config.bbq.publish_packet_rate {
+            diagnostics_properties.push(Property::float(
+                PROPERTY_ID_PACKET_RATE,
+                self.config.property_label(
+                    NODE_ID_DIAGNOSTICS,
+                    PROPERTY_ID_PACKET_RATE,
+                    "Real-time packet rate",
+                ),
+                false,
+                true,
+                Some("Hz"),
+                None,
+            ));
+        }
+        if self.config.bbq.publish_error_count {
+            diagnostics_properties.push(Property::integer(
+                PROPERTY_ID_PUBLISH_ERRORS,
+                self.config.property_label(
+                    NODE_ID_DIAGNOSTICS,
+                    PROPERTY_ID_PUBLISH_ERRORS,
+                    "Publish errors",
+                ),
+                false,
+                true,
+                None,
+                None,
+            ));
+        }
+        if !diagnostics_properties.is_empty() {
+            self.add_node(
+                homie,
+                Node::new(
+                    NODE_ID_DIAGNOSTICS,
+                    self.config.node_label(NODE_ID_DIAGNOSTICS, "Diagnostics"),
+                    "Diagnostics",
+                    diagnostics_properties,
+                ),
             )
             .await?;
+        }
+        self.add_node(
+            homie,
+            Node::new(
+                NODE_ID_TIMER,
+                self.config.node_label(NODE_ID_TIMER, "Timer"),
+                "Cooking timer",
+                vec![
+                    Property::boolean(
+                        PROPERTY_ID_TIMER_RUNNING,
+                        self.config.property_label(
+                            NODE_ID_TIMER,
+                            PROPERTY_ID_TIMER_RUNNING,
+                            "Running",
+                        ),
+                        true,
+                        true,
+                        None,
+                    ),
+                    Property::integer(
+                        PROPERTY_ID_TIMER_ELAPSED,
+                        self.config.property_label(
+                            NODE_ID_TIMER,
+                            PROPERTY_ID_TIMER_ELAPSED,
+                            "Elapsed time",
+                        ),
+                        false,
+                        true,
+                        Some("s"),
+                        None,
+                    ),
+                ],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The maximum duration of a cook before alarming, if one is configured.
+    fn max_cook_duration(&self) -> Option<Duration> {
+        self.config
+            .bbq
+            .max_cook_minutes
+            .map(|minutes| Duration::from_secs(minutes.saturating_mul(60)))
+    }
 
+    /// Create and connect a new Homie device, with property updates handled by `update_handler`.
+    async fn spawn_homie(
+        &self,
+        device_base: &str,
+        mqtt_options: MqttOptions,
+        update_handler: &UpdateHandler<D>,
+    ) -> Result<
+        (
+            HomieDevice,
+            impl Future<Output = Result<(), SpawnError>> + Unpin,
+        ),
+        Report,
+    > {
+        let mut homie_builder = HomieDevice::builder(device_base, &self.name, mqtt_options);
+        homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        let update_handler = update_handler.clone();
+        homie_builder.set_update_callback(move |node_id, property_id, value| {
+            update_handler
+                .clone()
+                .handle_update(node_id, property_id, value)
+        });
+        Ok(homie_builder.spawn().await?)
+    }
+
+    /// Add the same nodes to a new Homie device as `old_homie`, whose connection to the MQTT broker
+    /// failed, and publish all their values again.
+    async fn restore_homie(
+        &self,
+        old_homie: &HomieDevice,
+        homie: &mut HomieDevice,
+        ready: bool,
+    ) -> Result<(), Report> {
+        self.add_nodes(homie).await?;
+        if old_homie.has_node(NODE_ID_AVERAGE) {
+            self.add_average_node(homie).await?;
+        }
+        let probes = self.capabilities.lock().unwrap().probes;
+        for probe_index in 0..probes as u8 {
+            let node_id = format!("{}{}", NODE_ID_PROBE_PREFIX, probe_index);
+            if old_homie.has_node(&node_id) {
+                self.add_probe_node(homie, &node_id, probe_index).await?;
+            }
+        }
+        self.republish(homie).await?;
+        if ready {
+            homie.ready().await?;
+        }
         Ok(())
     }
-}
 
-async fn set_target(device: &BBQDevice, probe_index: u8, target: &Target) -> Result<(), Report> {
-    match target.mode {
-        TargetMode::None => device.remove_target(probe_index).await,
-        TargetMode::Single => {
-            device
-                .set_target_temp(probe_index, target.temperature_max)
-                .await
+    /// Check that a target can be set on the first probe and then cleared again, for the self-test.
+    async fn self_test_targets(&self) -> bool {
+        let result = async {
+            self.device.set_target_temp(0, SELF_TEST_TARGET).await?;
+            self.device.remove_target(0).await
         }
-        TargetMode::Range => {
-            device
-                .set_target_range(probe_index, target.temperature_min..target.temperature_max)
+        .await;
+        if let Err(e) = &result {
+            log::warn!("Self-test failed to set target on {}: {}", self.name, e);
+        }
+        result.is_ok()
+    }
+
+    /// Publish a value for the given property, logging and counting any failure rather than
+    /// returning it, so that a flaky MQTT connection doesn't stop the device.
+    async fn publish_value(
+        &self,
+        homie: &HomieDevice,
+        node_id: &str,
+        property_id: &str,
+        value: impl ToString,
+    ) {
+        let value = value.to_string();
+        self.published_values
+            .lock()
+            .unwrap()
+            .record(node_id, property_id, &value);
+        self.send_value(homie, node_id, property_id, value, true)
+            .await;
+    }
+
+    /// Publish a value for the given property without recording it for republishing, retained
+    /// according to the config or else `default_retained`. Failures are logged and counted as for
+    /// `publish_value`.
+    async fn send_value(
+        &self,
+        homie: &HomieDevice,
+        node_id: &str,
+        property_id: &str,
+        value: String,
+        default_retained: bool,
+    ) {
+        let result = if self.retained(node_id, property_id, default_retained) {
+            homie.publish_value(node_id, property_id, value).await
+        } else {
+            homie
+                .publish_nonretained_value(node_id, property_id, value)
                 .await
+        };
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to publish {}/{} for {}: {}",
+                node_id,
+                property_id,
+                self.name,
+                e
+            );
+            self.publish_errors.fetch_add(1, Ordering::Relaxed);
         }
     }
-    .wrap_err("Failed to set target temperature")
-}
 
-/// The target temperatures set for each probe.
-#[derive(Debug, Default)]
-struct TargetState {
-    /// Map from probe index to target settings.
-    targets: HashMap<u8, Target>,
-}
+    /// Remove the given node from the Homie device, along with the values published for it. Like
+    /// publishing, failing to remove it is logged and counted rather than stopping the device.
+    async fn remove_node(&self, homie: &mut HomieDevice, node_id: &str) {
+        self.published_values.lock().unwrap().remove_node(node_id);
+        if let Err(e) = homie.remove_node(node_id).await {
+            log::warn!("Failed to remove {} of {}: {}", node_id, self.name, e);
+            self.publish_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-impl TargetState {
-    fn target(&mut self, probe_index: u8) -> &mut Target {
-        self.targets.entry(probe_index).or_default()
+    /// Publish whether the alarm is sounding, which isn't retained unless configured to be.
+    async fn publish_alarm(&self, homie: &HomieDevice, alarm: bool) {
+        self.send_value(
+            homie,
+            NODE_ID_SETTINGS,
+            PROPERTY_ID_ALARM,
+            alarm.to_string(),
+            false,
+        )
+        .await;
     }
-}
 
-/// The target mode and temperature for a single probe.
-#[derive(Clone, Default, Debug)]
-struct Target {
-    mode: TargetMode,
-    temperature_min: f32,
-    temperature_max: f32,
-}
+    /// Whether the given property should be retained, according to the config or else `default`.
+    fn retained(&self, node_id: &str, property_id: &str, default: bool) -> bool {
+        // Probe nodes are all configured together, as for labels.
+        let node_id = if probe_id_to_index(node_id).is_some() {
+            NODE_ID_PROBE_PREFIX
+        } else {
+            node_id
+        };
+        self.config.property_retained(node_id, property_id, default)
+    }
 
-#[derive(Copy, Clone, Debug, Default)]
-enum TargetMode {
-    #[default]
-    None,
-    Single,
-    Range,
-}
+    /// Add the given node to the Homie device, with its properties retained or not as configured.
+    ///
+    /// Values which homie-device publishes itself in response to a property being set are always
+    /// retained, whatever the config.
+    async fn add_node(&self, homie: &mut HomieDevice, mut node: Node) -> Result<(), Report> {
+        for property in &mut node.properties {
+            property.retained = self.retained(&node.id, &property.id, property.retained);
+        }
+        homie.add_node(node).await?;
+        Ok(())
+    }
 
-impl FromStr for TargetMode {
-    type Err = Report;
+    /// Publish the latest value of every property again, for controllers which have missed them.
+    async fn republish(&self, homie: &HomieDevice) -> Result<(), Report> {
+        log::info!("Republishing all values for {}", self.name);
+        let current_unit = *self.current_unit.lock().unwrap();
+        self.publish_value(
+            homie,
+            NODE_ID_SETTINGS,
+            PROPERTY_ID_DISPLAY_UNIT,
+            display_unit(current_unit),
+        )
+        .await;
+        let (values, alarm) = {
+            let published_values = self.published_values.lock().unwrap();
+            (published_values.all(), published_values.alarm)
+        };
+        for ((node_id, property_id), value) in values {
+            self.publish_value(homie, &node_id, &property_id, value)
+                .await;
+        }
+        if let Some(alarm) = alarm {
+            self.publish_alarm(homie, alarm).await;
+        }
+        Ok(())
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            TARGET_MODE_NONE => Ok(Self::None),
-            TARGET_MODE_SINGLE => Ok(Self::Single),
-            TARGET_MODE_RANGE => Ok(Self::Range),
-            _ => bail!("Invalid target mode {}", s),
+    /// Publish whether the cooking timer is running and how long it has been running for.
+    async fn publish_timer(&self, homie: &HomieDevice, cook_timer: &CookTimer, now: Instant) {
+        self.publish_value(
+            homie,
+            NODE_ID_TIMER,
+            PROPERTY_ID_TIMER_RUNNING,
+            cook_timer.is_running(),
+        )
+        .await;
+        self.publish_value(
+            homie,
+            NODE_ID_TIMER,
+            PROPERTY_ID_TIMER_ELAPSED,
+            cook_timer.elapsed(now).as_secs(),
+        )
+        .await;
+    }
+
+    /// Warn that the cook has been running for longer than the configured maximum, and sound the
+    /// alarm if configured to.
+    async fn handle_cook_timeout(&self, homie: &HomieDevice) -> Result<(), Report> {
+        log::warn!("Maximum cook duration exceeded for {}", self.name);
+        self.notify(None, AlarmReason::CookTimeout, None);
+        self.publish_value(homie, NODE_ID_SETTINGS, PROPERTY_ID_COOK_TIMEOUT, true)
+            .await;
+        if self.config.bbq.cook_timeout_alarm {
+            if let Err(e) = self.device.sound_alarm().await {
+                log::warn!("Failed to sound alarm on {}: {}", self.name, e);
+            }
         }
+        Ok(())
     }
-}
 
-impl TargetMode {
-    fn as_str(&self) -> &'static str {
-        match self {
-            Self::None => TARGET_MODE_NONE,
-            Self::Single => TARGET_MODE_SINGLE,
-            Self::Range => TARGET_MODE_RANGE,
+    async fn handle_bridge_command(
+        &self,
+        command: BridgeCommand,
+        homie: &HomieDevice,
+        update_handler: &UpdateHandler<D>,
+    ) -> Result<(), Report> {
+        match command {
+            // This is handled by the main loop in `run`.
+            BridgeCommand::Shutdown => {}
+            BridgeCommand::SetUnit(unit) => {
+                update_handler.set_unit(unit).await?;
+                self.publish_value(
+                    homie,
+                    NODE_ID_SETTINGS,
+                    PROPERTY_ID_DISPLAY_UNIT,
+                    display_unit(unit),
+                )
+                .await;
+            }
         }
+        Ok(())
     }
-}
 
-impl Display for TargetMode {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.write_str(self.as_str())
+    async fn handle_setting_result(
+        &mut self,
+        result: SettingResult,
+        homie: &mut HomieDevice,
+    ) -> Result<(), Report> {
+        log::trace!("Setting result: {:?}", result);
+        match result {
+            SettingResult::BatteryLevel {
+                current_voltage,
+                max_voltage,
+            } => {
+                let percentage =
+                    battery_percentage(current_voltage, max_voltage, &self.device_config);
+                self.publish_value(homie, NODE_ID_BATTERY, PROPERTY_ID_VOLTAGE, current_voltage)
+                    .await;
+                self.publish_value(homie, NODE_ID_BATTERY, PROPERTY_ID_PERCENTAGE, percentage)
+                    .await;
+                let low = percentage < self.config.bbq.low_battery_percentage;
+                self.publish_value(homie, NODE_ID_BATTERY, PROPERTY_ID_LOW, low)
+                    .await;
+                self.summary.battery_percentage = Some(percentage);
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_battery_percentage(self.mac_address, percentage);
+                }
+                self.capabilities.lock().unwrap().battery = true;
+                self.initial_data.battery = true;
+                self.publish_summary(homie).await?;
+            }
+            SettingResult::SilencePressed => {
+                self.capabilities.lock().unwrap().alarm = Some(true);
+                if let Err(e) = rearm_hold_targets(
+                    &self.device,
+                    &self.target_state,
+                    &self.device_config.probe_offsets,
+                )
+                .await
+                {
+                    log::error!("Failed to re-arm alarm on {}: {}", self.name, e);
+                }
+                self.published_values.lock().unwrap().alarm = Some(false);
+                self.publish_alarm(homie, false).await;
+            }
+            _ => {}
+        }
+        Ok(())
     }
-}
 
-fn probe_id_to_index(probe_id: &str) -> Option<u8> {
-    probe_id.strip_prefix(NODE_ID_PROBE_PREFIX)?.parse().ok()
-}
+    /// Get the calibration offset configured for the given probe, or 0 if there is none.
+    fn probe_offset(&self, probe_index: u8) -> f32 {
+        probe_offset(&self.device_config.probe_offsets, probe_index)
+    }
 
-fn parse_display_unit(value: &str) -> Option<TemperatureUnit> {
-    match value {
-        DISPLAY_UNIT_CELCIUS => Some(TemperatureUnit::Celcius),
-        DISPLAY_UNIT_FAHRENHEIT => Some(TemperatureUnit::Fahrenheit),
-        _ => None,
+    /// Get the port number labelled on the device for the given probe, which defaults to counting
+    /// from 1 in the order the device reports them.
+    fn probe_port(&self, probe_index: u8) -> u32 {
+        self.device_config
+            .probe_ports
+            .get(probe_index as usize)
+            .copied()
+            .unwrap_or(u32::from(probe_index) + 1)
+    }
+
+    /// The unit in which to publish temperatures.
+    fn publish_unit(&self) -> TemperatureUnit {
+        publish_unit(self.config.bbq.publish_display_unit, &self.current_unit)
+    }
+
+    /// Publish Home Assistant discovery config for a sensor for the given property of `node`, if
+    /// discovery is enabled.
+    async fn publish_discovery(
+        &self,
+        node: &Node,
+        property_id: &str,
+        device_class: Option<&'static str>,
+        unit: Option<&str>,
+    ) {
+        let homeassistant = match &self.homeassistant {
+            Some(homeassistant) => homeassistant,
+            None => return,
+        };
+        let property_name = node
+            .properties
+            .iter()
+            .find(|property| property.id == property_id)
+            .map_or(property_id, |property| property.name.as_str());
+        let name = format!("{} {}", node.name, property_name);
+        if let Err(e) = homeassistant
+            .publish_sensor(&node.id, property_id, &name, device_class, unit)
+            .await
+        {
+            log::warn!(
+                "Failed to publish Home Assistant config for {}: {}",
+                self.name,
+                e
+            );
+        }
+    }
+
+    /// The unit in which temperatures are published, as Home Assistant expects it.
+    fn homeassistant_unit(&self) -> &'static str {
+        match self.publish_unit() {
+            TemperatureUnit::Celcius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+        }
+    }
+
+    async fn add_average_node(&self, homie: &mut HomieDevice) -> Result<(), Report> {
+        let node = self.average_node();
+        self.publish_discovery(
+            &node,
+            PROPERTY_ID_TEMPERATURE,
+            Some("temperature"),
+            Some(self.homeassistant_unit()),
+        )
+        .await;
+        self.add_node(homie, node).await?;
+        Ok(())
+    }
+
+    async fn add_probe_node(
+        &self,
+        homie: &mut HomieDevice,
+        node_id: &str,
+        probe_index: u8,
+    ) -> Result<(), Report> {
+        let node = self.node_for_probe(node_id, probe_index);
+        self.publish_discovery(
+            &node,
+            PROPERTY_ID_TEMPERATURE,
+            Some("temperature"),
+            Some(self.homeassistant_unit()),
+        )
+        .await;
+        self.add_node(homie, node).await?;
+        Ok(())
+    }
+
+    fn average_node(&self) -> Node {
+        Node::new(
+            NODE_ID_AVERAGE,
+            self.config.node_label(NODE_ID_AVERAGE, "Average"),
+            "Average temperature",
+            vec![Property::float(
+                PROPERTY_ID_TEMPERATURE,
+                self.config
+                    .property_label(NODE_ID_AVERAGE, PROPERTY_ID_TEMPERATURE, "Temperature"),
+                false,
+                true,
+                Some(display_unit(self.publish_unit())),
+                None,
+            )],
+        )
+    }
+
+    fn node_for_probe(&self, node_id: &str, probe_index: u8) -> Node {
+        let unit = display_unit(self.publish_unit());
+        let rate_unit = format!("{}/min", unit);
+        let default_probe_name = format!(
+            "{} {}",
+            self.config.node_label(NODE_ID_PROBE_PREFIX, "Probe"),
+            probe_index + 1
+        );
+        let probe_name = self
+            .device_config
+            .probe_names
+            .get(probe_index as usize)
+            .unwrap_or(&default_probe_name);
+        let mut properties = vec![
+            Property::float(
+                PROPERTY_ID_TEMPERATURE,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TEMPERATURE,
+                    "Temperature",
+                ),
+                false,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+                    "Minimum temperature",
+                ),
+                true,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+                    "Target/maximum temperature",
+                ),
+                true,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::enumeration(
+                PROPERTY_ID_TARGET_MODE,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_MODE,
+                    "Target mode",
+                ),
+                true,
+                true,
+                None,
+                &TARGET_MODES,
+            ),
+            Property::string(
+                PROPERTY_ID_TARGET_COMMAND,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_COMMAND,
+                    "Set target",
+                ),
+                true,
+                false,
+                None,
+            ),
+            Property::boolean(
+                PROPERTY_ID_TARGET_REACHED,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_REACHED,
+                    "Target reached",
+                ),
+                false,
+                true,
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_SETPOINT,
+                self.config
+                    .property_label(NODE_ID_PROBE_PREFIX, PROPERTY_ID_SETPOINT, "Setpoint"),
+                true,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::integer(
+                PROPERTY_ID_PORT,
+                self.config
+                    .property_label(NODE_ID_PROBE_PREFIX, PROPERTY_ID_PORT, "Port"),
+                false,
+                true,
+                None,
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_RECORDED_MIN,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_RECORDED_MIN,
+                    "Lowest temperature",
+                ),
+                false,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_RECORDED_MAX,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_RECORDED_MAX,
+                    "Highest temperature",
+                ),
+                false,
+                true,
+                Some(unit),
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_RATE,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_RATE,
+                    "Rate of change",
+                ),
+                false,
+                true,
+                Some(&rate_unit),
+                None,
+            ),
+            Property::integer(
+                PROPERTY_ID_ETA,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_ETA,
+                    "Estimated time to target",
+                ),
+                false,
+                true,
+                Some("s"),
+                None,
+            ),
+            Property::boolean(
+                PROPERTY_ID_RESET_RECORDED,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_RESET_RECORDED,
+                    "Reset lowest and highest temperatures",
+                ),
+                true,
+                false,
+                None,
+            ),
+            Property::enumeration(
+                PROPERTY_ID_ALARM_CONDITION,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_ALARM_CONDITION,
+                    "Alarm condition",
+                ),
+                false,
+                true,
+                None,
+                &ALARM_CONDITIONS,
+            ),
+        ];
+        if self.config.bbq.publish_other_unit {
+            let (property_id, other_unit) = other_unit(self.publish_unit());
+            let label = match other_unit {
+                TemperatureUnit::Celcius => "Temperature (ºC)",
+                TemperatureUnit::Fahrenheit => "Temperature (ºF)",
+            };
+            properties.push(Property::float(
+                property_id,
+                self.config
+                    .property_label(NODE_ID_PROBE_PREFIX, property_id, label),
+                false,
+                true,
+                Some(display_unit(other_unit)),
+                None,
+            ));
+        }
+        if !self.config.bbq.target_presets.is_empty() {
+            let mut preset_names: Vec<&str> = self
+                .config
+                .bbq
+                .target_presets
+                .keys()
+                .map(String::as_str)
+                .collect();
+            preset_names.sort_unstable();
+            properties.push(Property::enumeration(
+                PROPERTY_ID_TARGET_PRESET,
+                self.config.property_label(
+                    NODE_ID_PROBE_PREFIX,
+                    PROPERTY_ID_TARGET_PRESET,
+                    "Target preset",
+                ),
+                true,
+                false,
+                None,
+                &preset_names,
+            ));
+        }
+        Node::new(node_id, probe_name, "Temperature probe", properties)
+    }
+
+    async fn handle_realtime_data(
+        &mut self,
+        data: RealTimeData,
+        homie: &mut HomieDevice,
+    ) -> Result<(), Report> {
+        log::trace!("Realtime data: {:?}", data);
+        self.initial_data.real_time = true;
+        if self.config.bbq.publish_packet_rate {
+            self.publish_packet_rate(homie).await?;
+        }
+        self.summary.probe_temperatures.clear();
+        self.capabilities.lock().unwrap().probes = data.probe_temperatures.len();
+        let mut average_temperatures = vec![];
+        let realtime_unit = self
+            .device_config
+            .realtime_unit
+            .unit(*self.current_unit.lock().unwrap());
+        let publish_unit = self.publish_unit();
+        let precision = self.config.bbq.temperature_precision;
+        for (probe_index, temperature) in data.probe_temperatures.into_iter().enumerate() {
+            let node_id = format!("{}{}", NODE_ID_PROBE_PREFIX, probe_index);
+            let exists = homie.has_node(&node_id);
+            if let Some(temperature) = temperature {
+                self.missing_readings.remove(&(probe_index as u8));
+                let temperature =
+                    to_celsius(temperature, realtime_unit) + self.probe_offset(probe_index as u8);
+                let temperature = smooth(
+                    self.recent_readings.entry(probe_index as u8).or_default(),
+                    self.config.bbq.smoothing_window,
+                    temperature,
+                );
+                log::trace!(
+                    mac:% = self.mac_address, probe = probe_index, temperature;
+                    "Probe {} of {} is at {}ºC",
+                    probe_index + 1,
+                    self.name,
+                    temperature
+                );
+                self.summary
+                    .probe_temperatures
+                    .push(display_temperature(temperature, publish_unit));
+                if self
+                    .device_config
+                    .average_probes
+                    .contains(&(probe_index as u8))
+                {
+                    average_temperatures.push(temperature);
+                }
+                if !exists {
+                    self.add_probe(homie, probe_index as u8, &node_id).await?;
+                }
+                let now = Instant::now();
+                // The rate and ETA are derived from the temperature, so are rate limited with it.
+                let publish_due = temperature_publish_due(
+                    self.last_temperature_publishes.get(&(probe_index as u8)),
+                    temperature,
+                    now,
+                    &self.config.bbq,
+                );
+                if publish_due {
+                    self.publish_value(
+                        homie,
+                        &node_id,
+                        PROPERTY_ID_TEMPERATURE,
+                        format_temperature(temperature, publish_unit, precision),
+                    )
+                    .await;
+                    if self.config.bbq.publish_other_unit {
+                        let (property_id, other_unit) = other_unit(publish_unit);
+                        self.publish_value(
+                            homie,
+                            &node_id,
+                            property_id,
+                            format_temperature(temperature, other_unit, precision),
+                        )
+                        .await;
+                    }
+                    self.last_temperature_publishes
+                        .insert(probe_index as u8, (temperature, now));
+                }
+                self.push_reading(probe_index as u8, temperature);
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_probe_temperature(self.mac_address, probe_index as u8, temperature);
+                }
+                let target = self.target_state.lock().await.target(probe_index as u8);
+                let target = target.lock().await.clone();
+                let reached = target.reached(temperature, self.config.bbq.target_comparison);
+                let previously_reached = self.targets_reached.insert(probe_index as u8, reached);
+                if reached && previously_reached != Some(true) {
+                    self.notify(
+                        Some(probe_index as u8),
+                        AlarmReason::TargetReached,
+                        Some(temperature),
+                    );
+                }
+                if previously_reached != Some(reached) {
+                    self.publish_value(homie, &node_id, PROPERTY_ID_TARGET_REACHED, reached)
+                        .await;
+                }
+                self.update_alarm_condition(homie, probe_index as u8, &node_id, temperature)
+                    .await;
+                // Only publish the recorded temperatures when they change, which includes the
+                // first reading after they are reset.
+                let mut recorded_changed = false;
+                let recorded = self
+                    .recorded_temperatures
+                    .entry(probe_index as u8)
+                    .or_insert_with(|| {
+                        recorded_changed = true;
+                        RecordedTemperatures::new(temperature)
+                    });
+                recorded_changed |= recorded.record(temperature);
+                let recorded = recorded.clone();
+                if recorded_changed {
+                    self.publish_recorded(homie, &node_id, &recorded).await;
+                }
+                let rate = self
+                    .trends
+                    .entry(probe_index as u8)
+                    .or_default()
+                    .record(temperature, now);
+                if publish_due {
+                    // There is no rate until there have been two readings.
+                    if let Some(rate) = rate {
+                        self.publish_value(
+                            homie,
+                            &node_id,
+                            PROPERTY_ID_RATE,
+                            format_difference(rate, publish_unit, precision),
+                        )
+                        .await;
+                    }
+                    let eta = estimate_time_to_target(temperature, &target, rate)
+                        .map_or(ETA_UNKNOWN, |eta| eta.as_secs() as i64);
+                    self.publish_value(homie, &node_id, PROPERTY_ID_ETA, eta)
+                        .await;
+                }
+            } else if exists {
+                // Spurious missing readings are common, so wait a little while before removing the
+                // probe in case it comes back.
+                let now = Instant::now();
+                let missing = self
+                    .missing_readings
+                    .entry(probe_index as u8)
+                    .or_insert((0, now));
+                missing.0 += 1;
+                if !probe_removal_due(*missing, now, &self.config.bbq) {
+                    continue;
+                }
+                self.missing_readings.remove(&(probe_index as u8));
+                if let Some(metrics) = &self.metrics {
+                    metrics.remove_probe(self.mac_address, probe_index as u8);
+                }
+                self.alarm_conditions.remove(&(probe_index as u8));
+                self.recorded_temperatures.remove(&(probe_index as u8));
+                self.recent_readings.remove(&(probe_index as u8));
+                self.last_temperature_publishes.remove(&(probe_index as u8));
+                self.trends.remove(&(probe_index as u8));
+                self.targets_reached.remove(&(probe_index as u8));
+                if let Some(homeassistant) = &self.homeassistant {
+                    if let Err(e) = homeassistant
+                        .remove_sensor(&node_id, PROPERTY_ID_TEMPERATURE)
+                        .await
+                    {
+                        log::warn!(
+                            "Failed to remove Home Assistant sensor for {} of {}: {}",
+                            node_id,
+                            self.name,
+                            e
+                        );
+                        self.publish_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                self.remove_node(homie, &node_id).await;
+            }
+        }
+        let now = Instant::now();
+        if let Some(average) = average(&average_temperatures).filter(|&average| {
+            temperature_publish_due(
+                self.last_average_publish.as_ref(),
+                average,
+                now,
+                &self.config.bbq,
+            )
+        }) {
+            self.last_average_publish = Some((average, now));
+            self.publish_value(
+                homie,
+                NODE_ID_AVERAGE,
+                PROPERTY_ID_TEMPERATURE,
+                format_temperature(average, publish_unit, precision),
+            )
+            .await;
+        }
+        self.publish_summary(homie).await
+    }
+
+    /// Publish whether the given probe is outside its configured alarm thresholds, and if it has
+    /// just gone outside them then warn, and sound the alarm if configured to.
+    async fn update_alarm_condition(
+        &mut self,
+        homie: &HomieDevice,
+        probe_index: u8,
+        node_id: &str,
+        temperature: f32,
+    ) {
+        let condition = AlarmCondition::new(
+            temperature,
+            self.device_config
+                .probe_alarms
+                .get(usize::from(probe_index)),
+        );
+        let previous = self.alarm_conditions.insert(probe_index, condition);
+        if previous == Some(condition) {
+            return;
+        }
+        self.publish_value(homie, node_id, PROPERTY_ID_ALARM_CONDITION, condition)
+            .await;
+        if condition == AlarmCondition::Normal {
+            return;
+        }
+        log::warn!(
+            "Probe {} of {} is {} at {}ºC",
+            probe_index + 1,
+            self.name,
+            condition,
+            temperature
+        );
+        let reason = if condition == AlarmCondition::Low {
+            AlarmReason::Low
+        } else {
+            AlarmReason::High
+        };
+        self.notify(Some(probe_index), reason, Some(temperature));
+        if self.config.bbq.probe_alarm_sound {
+            if let Err(e) = self.device.sound_alarm().await {
+                log::warn!("Failed to sound alarm on {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// Send a notification that the device alarmed to the webhook, if it is enabled. If the webhook
+    /// can't keep up then the notification is dropped rather than holding up the device.
+    fn notify(&self, probe: Option<u8>, reason: AlarmReason, temperature: Option<f32>) {
+        if let Some(notifications) = &self.notifications {
+            let notification = Notification {
+                device: self.name.clone(),
+                probe,
+                reason,
+                temperature,
+            };
+            if let Err(TrySendError::Full(_)) = notifications.try_send(notification) {
+                log::warn!("Notification queue full, dropping alarm from {}", self.name);
+            }
+        }
+    }
+
+    /// Send a reading to be pushed to the REST endpoint, if it is enabled. If the endpoint can't
+    /// keep up then the reading is dropped rather than holding up the device.
+    fn push_reading(&self, probe_index: u8, temperature: f32) {
+        if let Some(push_readings) = &self.push_readings {
+            let reading = Reading::new(self.mac_address.to_string(), probe_index, temperature);
+            if let Err(TrySendError::Full(_)) = push_readings.try_send(reading) {
+                log::warn!("Push queue full, dropping reading from {}", self.name);
+            }
+        }
+    }
+
+    /// Record that a real-time data packet was received, and publish the average rate if it is due.
+    async fn publish_packet_rate(&mut self, homie: &HomieDevice) -> Result<(), Report> {
+        let now = Instant::now();
+        self.packet_rate.record(now);
+        if matches!(self.packet_rate.last_published, Some(last) if now - last < PACKET_RATE_INTERVAL)
+        {
+            return Ok(());
+        }
+        if let Some(rate) = self.packet_rate.rate() {
+            self.publish_value(homie, NODE_ID_DIAGNOSTICS, PROPERTY_ID_PACKET_RATE, rate)
+                .await;
+            self.packet_rate.last_published = Some(now);
+        }
+        Ok(())
+    }
+
+    /// Publish the human-readable summary of the device, if it is enabled and has changed, but not
+    /// more often than the configured interval.
+    async fn publish_summary(&mut self, homie: &HomieDevice) -> Result<(), Report> {
+        let template = match &self.config.bbq.summary_template {
+            Some(template) => template,
+            None => return Ok(()),
+        };
+        let interval = Duration::from_secs(self.config.bbq.summary_interval_seconds);
+        if matches!(self.summary.last_published, Some(last) if last.elapsed() < interval) {
+            return Ok(());
+        }
+        let summary = self
+            .summary
+            .format(template, &self.name, display_unit(self.publish_unit()));
+        if self.summary.last_summary.as_ref() != Some(&summary) {
+            self.publish_value(homie, NODE_ID_STATUS, PROPERTY_ID_SUMMARY, &summary)
+                .await;
+            self.summary.last_summary = Some(summary);
+            self.summary.last_published = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Publish the capabilities of the device as JSON, if they have changed since they were last
+    /// published.
+    async fn publish_capabilities(&self, homie: &HomieDevice) -> Result<(), Report> {
+        let json = {
+            let mut capabilities = self.capabilities.lock().unwrap();
+            let json = serde_json::to_string(&*capabilities)?;
+            if capabilities.last_published.as_ref() == Some(&json) {
+                return Ok(());
+            }
+            capabilities.last_published = Some(json.clone());
+            json
+        };
+        self.publish_value(homie, NODE_ID_INFO, PROPERTY_ID_CAPABILITIES, json)
+            .await;
+        Ok(())
+    }
+
+    async fn add_probe(
+        &self,
+        homie: &mut HomieDevice,
+        probe_index: u8,
+        node_id: &str,
+    ) -> Result<(), Report> {
+        self.add_probe_node(homie, node_id, probe_index).await?;
+        self.publish_value(
+            homie,
+            node_id,
+            PROPERTY_ID_PORT,
+            self.probe_port(probe_index),
+        )
+        .await;
+
+        // Restore the target temperature to its previous value, or none.
+        let target = self.target_state.lock().await.target(probe_index);
+        let target = target.lock().await;
+        let result = set_target(
+            &self.device,
+            probe_index,
+            &target,
+            self.probe_offset(probe_index),
+        )
+        .await;
+        self.capabilities.lock().unwrap().targets = Some(result.is_ok());
+        result?;
+        self.publish_target(homie, probe_index, &target).await
+    }
+
+    /// Add all probe nodes and the average node again, so that their temperature properties have
+    /// the current unit, and publish their targets in it.
+    async fn update_probe_units(&self, homie: &mut HomieDevice) -> Result<(), Report> {
+        if homie.has_node(NODE_ID_AVERAGE) {
+            self.remove_node(homie, NODE_ID_AVERAGE).await;
+            self.add_average_node(homie).await?;
+        }
+        let probes = self.capabilities.lock().unwrap().probes;
+        for probe_index in 0..probes as u8 {
+            let node_id = format!("{}{}", NODE_ID_PROBE_PREFIX, probe_index);
+            if !homie.has_node(&node_id) {
+                continue;
+            }
+            self.remove_node(homie, &node_id).await;
+            self.add_probe_node(homie, &node_id, probe_index).await?;
+            self.publish_value(
+                homie,
+                &node_id,
+                PROPERTY_ID_PORT,
+                self.probe_port(probe_index),
+            )
+            .await;
+            let target = self.target_state.lock().await.target(probe_index);
+            let target = target.lock().await;
+            self.publish_target(homie, probe_index, &target).await?;
+            // These are otherwise only published when they change, so publish them in the new
+            // unit now.
+            if let Some(recorded) = self.recorded_temperatures.get(&probe_index) {
+                self.publish_recorded(homie, &node_id, recorded).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish the lowest and highest temperatures recorded by a probe.
+    async fn publish_recorded(
+        &self,
+        homie: &HomieDevice,
+        node_id: &str,
+        recorded: &RecordedTemperatures,
+    ) {
+        let publish_unit = self.publish_unit();
+        let precision = self.config.bbq.temperature_precision;
+        self.publish_value(
+            homie,
+            node_id,
+            PROPERTY_ID_RECORDED_MIN,
+            format_temperature(recorded.min, publish_unit, precision),
+        )
+        .await;
+        self.publish_value(
+            homie,
+            node_id,
+            PROPERTY_ID_RECORDED_MAX,
+            format_temperature(recorded.max, publish_unit, precision),
+        )
+        .await;
+    }
+
+    /// Publish the target properties for the given probe.
+    async fn publish_target(
+        &self,
+        homie: &HomieDevice,
+        probe_index: u8,
+        target: &Target,
+    ) -> Result<(), Report> {
+        let node_id = format!("{}{}", NODE_ID_PROBE_PREFIX, probe_index);
+        if !homie.has_node(&node_id) {
+            return Ok(());
+        }
+        self.publish_value(homie, &node_id, PROPERTY_ID_TARGET_MODE, target.mode)
+            .await;
+        let unset_target_value = self.config.bbq.unset_target_value;
+        for (property_id, value) in target.property_values(
+            unset_target_value,
+            self.publish_unit(),
+            self.config.bbq.temperature_precision,
+        ) {
+            self.publish_value(homie, &node_id, property_id, value)
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Handles properties being set over MQTT, from the Homie update callback.
+#[derive(Clone, Debug)]
+struct UpdateHandler<D: Thermometer> {
+    device: D,
+    target_state: Arc<AsyncMutex<TargetState>>,
+    capabilities: Arc<Mutex<Capabilities>>,
+    current_unit: Arc<Mutex<TemperatureUnit>>,
+    /// Targets which have been changed, so their properties need publishing.
+    target_updates: UnboundedSender<(u8, Target)>,
+    /// Requests to publish all values again.
+    republish_requests: UnboundedSender<()>,
+    /// Requests to reset the lowest and highest temperatures recorded by the given probe.
+    recorded_resets: UnboundedSender<u8>,
+    /// Requests to start (`true`) or stop (`false`) the cooking timer.
+    timer_commands: UnboundedSender<bool>,
+    /// Notifications that the display unit has changed, if temperatures are published in it.
+    unit_updates: Option<UnboundedSender<()>>,
+    /// Calibration offsets of each probe, by index.
+    probe_offsets: Vec<f32>,
+    resync_targets_on_unit_change: bool,
+    /// The file to save targets to when they change, and the MAC address to save them under, if
+    /// they are to be saved.
+    state_file: Option<(String, MacAddress)>,
+    /// Named targets which can be applied to any probe.
+    target_presets: HashMap<String, TargetPreset>,
+}
+
+impl<D: Thermometer> UpdateHandler<D> {
+    async fn handle_update(
+        self,
+        node_id: String,
+        property_id: String,
+        value: String,
+    ) -> Option<String> {
+        let device = &self.device;
+        let capabilities = &self.capabilities;
+        log::trace!("{}/{} = {}", node_id, property_id, value);
+        if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_DISPLAY_UNIT {
+            let unit = parse_display_unit(&value)?;
+            if let Err(e) = self.set_unit(unit).await {
+                log::error!("Failed to set temperature unit: {}", e);
+                return None;
+            }
+            // Publish the canonical form, even if a short one was set.
+            Some(display_unit(unit).to_owned())
+        } else if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_ALARM_TONE {
+            if !device.alarm_tones().contains(&value.as_str()) {
+                log::warn!("Invalid alarm tone {:?}", value);
+                return None;
+            }
+            if let Err(e) = device.set_alarm_tone(&value).await {
+                log::error!("Failed to set alarm tone: {}", e);
+                return None;
+            }
+            Some(value)
+        } else if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_ALARM {
+            let state: bool = value.parse().ok()?;
+            if !state {
+                let result = device.silence_alarm().await;
+                capabilities.lock().unwrap().alarm = Some(result.is_ok());
+                if let Err(e) = result {
+                    log::error!("Failed to silence alarm: {}", e);
+                    return None;
+                }
+                if let Err(e) =
+                    rearm_hold_targets(device, &self.target_state, &self.probe_offsets).await
+                {
+                    log::error!("Failed to re-arm alarm: {}", e);
+                }
+                Some(value)
+            } else {
+                // Sound the alarm, such as to find the device. Not all devices can, in which case
+                // publish that it isn't sounding.
+                if let Err(e) = device.sound_alarm().await {
+                    log::warn!("Failed to sound alarm: {}", e);
+                    return Some(false.to_string());
+                }
+                Some(value)
+            }
+        } else if node_id == NODE_ID_TIMER && property_id == PROPERTY_ID_TIMER_RUNNING {
+            let running: bool = value.parse().ok()?;
+            if self.timer_commands.unbounded_send(running).is_err() {
+                log::error!("Failed to send timer command");
+                return None;
+            }
+            Some(value)
+        } else if node_id == NODE_ID_SETTINGS && property_id == PROPERTY_ID_REPUBLISH {
+            let republish: bool = value.parse().ok()?;
+            if republish && self.republish_requests.unbounded_send(()).is_err() {
+                log::error!("Failed to request republish");
+                return None;
+            }
+            Some(value)
+        } else if let Some(probe_index) = probe_id_to_index(&node_id) {
+            if property_id == PROPERTY_ID_RESET_RECORDED {
+                let reset: bool = value.parse().ok()?;
+                if reset && self.recorded_resets.unbounded_send(probe_index).is_err() {
+                    log::error!("Failed to request reset of recorded temperatures");
+                    return None;
+                }
+                return Some(value);
+            }
+            let target = self.target_state.lock().await.target(probe_index);
+            let offset = probe_offset(&self.probe_offsets, probe_index);
+            if property_id == PROPERTY_ID_TARGET_PRESET {
+                let preset = match self.target_presets.get(&value) {
+                    Some(preset) => preset,
+                    None => {
+                        log::warn!("Unknown target preset {:?}", value);
+                        return None;
+                    }
+                };
+                let new_target = Target::from_preset(preset);
+                // Keep the target locked while it is set, as in `update_target`.
+                let mut target = target.lock().await;
+                let result = set_target(device, probe_index, &new_target, offset).await;
+                capabilities.lock().unwrap().targets = Some(result.is_ok());
+                if let Err(e) = result {
+                    log::error!("Failed to set target preset {}: {}", value, e);
+                    return None;
+                }
+                *target = new_target.clone();
+                drop(target);
+                self.send_target_update(probe_index, new_target);
+                self.save_targets().await;
+                return Some(value);
+            }
+            let unit = publish_unit(self.unit_updates.is_some(), &self.current_unit);
+            let target = update_target(&target, &property_id, &value, unit, |target| async move {
+                let result = set_target(device, probe_index, &target, offset).await;
+                capabilities.lock().unwrap().targets = Some(result.is_ok());
+                result
+            })
+            .await?;
+            // Publish the other target properties, as they may have been changed too.
+            self.send_target_update(probe_index, target);
+            self.save_targets().await;
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Set the display unit of the device, and then set all targets again if configured to, in
+    /// case the device interprets them differently in the new unit.
+    async fn set_unit(&self, unit: TemperatureUnit) -> Result<(), Report> {
+        let result = self.device.set_temperature_unit(unit).await;
+        self.capabilities.lock().unwrap().unit = Some(result.is_ok());
+        result?;
+        *self.current_unit.lock().unwrap() = unit;
+        if let Some(unit_updates) = &self.unit_updates {
+            if unit_updates.unbounded_send(()).is_err() {
+                log::error!("Failed to send unit update");
+            }
+        }
+        if self.resync_targets_on_unit_change {
+            let targets = self.target_state.lock().await.all();
+            for (probe_index, target) in targets {
+                let target = target.lock().await;
+                let offset = probe_offset(&self.probe_offsets, probe_index);
+                set_target(&self.device, probe_index, &target, offset).await?;
+                self.send_target_update(probe_index, target.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Save all targets to the state file, if there is one.
+    async fn save_targets(&self) {
+        if let Some((state_file, mac_address)) = &self.state_file {
+            let targets = self.target_state.lock().await.all();
+            let mut saved_targets = HashMap::new();
+            for (probe_index, target) in targets {
+                saved_targets.insert(probe_index, target.lock().await.clone());
+            }
+            if let Err(e) = save_state(state_file, *mac_address, &saved_targets) {
+                log::warn!("Failed to save targets: {:?}", e);
+            }
+        }
+    }
+
+    fn send_target_update(&self, probe_index: u8, target: Target) {
+        if self
+            .target_updates
+            .unbounded_send((probe_index, target))
+            .is_err()
+        {
+            log::error!("Failed to send target update for probe {}", probe_index);
+        }
+    }
+}
+
+/// Update the given property of a probe's target from a value in the given unit, and then apply the
+/// new target with `apply`.
+///
+/// The target stays locked until `apply` has finished, so that concurrent updates to the same probe
+/// are applied in the same order that they are made, and the last one applied always reflects the
+/// combination of all of them.
+async fn update_target<F, Fut>(
+    target: &AsyncMutex<Target>,
+    property_id: &str,
+    value: &str,
+    unit: TemperatureUnit,
+    apply: F,
+) -> Option<Target>
+where
+    F: FnOnce(Target) -> Fut,
+    Fut: Future<Output = Result<(), Report>>,
+{
+    let mut target = target.lock().await;
+    let mut new_target = target.clone();
+    let temperature = || value.parse().ok().map(|value| to_celsius(value, unit));
+    match property_id {
+        PROPERTY_ID_TARGET_TEMPERATURE_MIN => {
+            new_target.temperature_min = temperature()?;
+        }
+        PROPERTY_ID_TARGET_TEMPERATURE_MAX => {
+            new_target.temperature_max = temperature()?;
+        }
+        PROPERTY_ID_TARGET_MODE => {
+            new_target.mode = value.parse().ok()?;
+            // Forget the old temperatures, so that they aren't silently used again if another
+            // mode is chosen later.
+            if new_target.mode == TargetMode::None {
+                new_target = Target::default();
+            }
+        }
+        PROPERTY_ID_SETPOINT => {
+            new_target.temperature_max = temperature()?;
+            new_target.mode = TargetMode::Single;
+        }
+        PROPERTY_ID_TARGET_COMMAND => {
+            let command: TargetCommand = match serde_json::from_str(value) {
+                Ok(command) => command,
+                Err(e) => {
+                    log::warn!("Invalid target command {:?}: {}", value, e);
+                    return None;
+                }
+            };
+            new_target = match command.apply(&new_target, unit) {
+                Ok(new_target) => new_target,
+                Err(e) => {
+                    log::warn!("Invalid target command {:?}: {}", value, e);
+                    return None;
+                }
+            };
+        }
+        _ => return None,
+    };
+    if !new_target.range_valid() {
+        log::warn!(
+            "Rejecting {} = {}, as the target minimum {} must be below the maximum {}",
+            property_id,
+            value,
+            new_target.temperature_min,
+            new_target.temperature_max
+        );
+        return None;
+    }
+    *target = new_target;
+    if let Err(e) = apply(target.clone()).await {
+        log::error!("Failed to set target temperature: {}", e);
+        return None;
+    }
+    Some(target.clone())
+}
+
+/// Set the given target on the device. The target is in calibrated temperatures, so the probe's
+/// calibration `offset` is taken off to get the temperature the device will see.
+async fn set_target(
+    device: &impl Thermometer,
+    probe_index: u8,
+    target: &Target,
+    offset: f32,
+) -> Result<(), Report> {
+    let temperature_min = target.temperature_min - offset;
+    let temperature_max = target.temperature_max - offset;
+    match target.mode {
+        TargetMode::None => device.remove_target(probe_index).await,
+        TargetMode::Single => device.set_target_temp(probe_index, temperature_max).await,
+        TargetMode::Range | TargetMode::Hold => {
+            device
+                .set_target_range(probe_index, temperature_min..temperature_max)
+                .await
+        }
+    }
+    .wrap_err("Failed to set target temperature")
+}
+
+/// Set all targets in hold mode on the device again, so that it alarms again if their probes are
+/// still outside the range after the alarm has been silenced.
+async fn rearm_hold_targets(
+    device: &impl Thermometer,
+    target_state: &AsyncMutex<TargetState>,
+    probe_offsets: &[f32],
+) -> Result<(), Report> {
+    let targets = target_state.lock().await.all();
+    for (probe_index, target) in targets {
+        let target = target.lock().await;
+        if target.mode == TargetMode::Hold {
+            let offset = probe_offset(probe_offsets, probe_index);
+            set_target(device, probe_index, &target, offset).await?;
+        }
+    }
+    Ok(())
+}
+
+/// What the bridge has found that the device supports, built up as each feature is used. Features
+/// which haven't been tried yet are `None`.
+#[derive(Debug, Default, Serialize)]
+struct Capabilities {
+    /// The number of probe sockets which the device reports.
+    probes: usize,
+    /// Whether setting targets on the device works.
+    targets: Option<bool>,
+    /// Whether silencing the alarm works.
+    alarm: Option<bool>,
+    /// Whether setting the display unit works.
+    unit: Option<bool>,
+    /// Whether the device has reported its battery level.
+    battery: bool,
+    /// The alarm tones which can be chosen between, if any.
+    alarm_tones: &'static [&'static str],
+    #[serde(skip)]
+    last_published: Option<String>,
+}
+
+/// Why the main loop of `Bbq::run` stopped.
+#[derive(Debug)]
+enum Stop {
+    /// Shutdown was requested.
+    Shutdown,
+    /// A complete set of readings was published, and only one was wanted.
+    Finished,
+    /// The device stopped sending data, without shutdown being requested, and couldn't be
+    /// reconnected to.
+    StreamsEnded,
+    /// Handling something from the device failed.
+    Failed(Report),
+}
+
+impl Stop {
+    /// Convert to the result to return from `Bbq::run`. Only an explicit shutdown or finishing in
+    /// `--once` mode counts as success, so that the device is reconnected in any other case.
+    fn into_result(self, name: &str) -> Result<(), Report> {
+        match self {
+            Self::Shutdown | Self::Finished => Ok(()),
+            Self::StreamsEnded => bail!("Data from {} stopped unexpectedly", name),
+            Self::Failed(e) => Err(e),
+        }
+    }
+}
+
+const SELF_TEST_PASSED: &str = "passed";
+
+/// Describe the result of the self-test, given what data has been received and whether setting and
+/// clearing a target worked.
+fn self_test_result(initial_data: &InitialData, targets_ok: bool) -> String {
+    let mut failures = vec![];
+    if !initial_data.real_time {
+        failures.push("no real-time data");
+    }
+    if !initial_data.battery {
+        failures.push("no battery level");
+    }
+    if !targets_ok {
+        failures.push("setting target failed");
+    }
+    if failures.is_empty() {
+        SELF_TEST_PASSED.to_owned()
+    } else {
+        format!("failed: {}", failures.join(", "))
+    }
+}
+
+/// The latest value published for each property, so they can all be published again.
+#[derive(Debug, Default)]
+struct PublishedValues {
+    /// Map from (node ID, property ID) to value.
+    values: HashMap<(String, String), String>,
+    /// The last known state of the alarm, which is published without being retained.
+    alarm: Option<bool>,
+}
+
+impl PublishedValues {
+    fn record(&mut self, node_id: &str, property_id: &str, value: &str) {
+        self.values.insert(
+            (node_id.to_owned(), property_id.to_owned()),
+            value.to_owned(),
+        );
+    }
+
+    /// Forget the values for a node which has been removed.
+    fn remove_node(&mut self, node_id: &str) {
+        self.values.retain(|(node, _), _| node != node_id);
+    }
+
+    fn all(&self) -> Vec<((String, String), String)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Which types of data have been received from the device since it was connected.
+#[derive(Debug, Default)]
+struct InitialData {
+    real_time: bool,
+    battery: bool,
+}
+
+impl InitialData {
+    fn is_complete(&self) -> bool {
+        self.real_time && self.battery
+    }
+}
+
+/// Arrival times of recent real-time data packets, to calculate the average rate.
+#[derive(Debug, Default)]
+struct PacketRate {
+    arrivals: VecDeque<Instant>,
+    last_published: Option<Instant>,
+}
+
+impl PacketRate {
+    /// Record a packet arriving at the given time, and forget any older than the window.
+    fn record(&mut self, now: Instant) {
+        self.arrivals.push_back(now);
+        while matches!(self.arrivals.front(), Some(&first) if now - first > PACKET_RATE_WINDOW) {
+            self.arrivals.pop_front();
+        }
+    }
+
+    /// The average number of packets per second over the window, if enough have arrived to tell.
+    fn rate(&self) -> Option<f32> {
+        let first = self.arrivals.front()?;
+        let last = self.arrivals.back()?;
+        let elapsed = (*last - *first).as_secs_f32();
+        if elapsed > 0.0 {
+            Some((self.arrivals.len() - 1) as f32 / elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+/// The latest values to include in the human-readable summary of a device.
+#[derive(Debug, Default)]
+struct Summary {
+    /// The temperatures of the connected probes, in the unit to publish.
+    probe_temperatures: Vec<f32>,
+    battery_percentage: Option<u32>,
+    last_summary: Option<String>,
+    last_published: Option<Instant>,
+}
+
+impl Summary {
+    /// Fill in the placeholders `{name}`, `{probes}`, `{max}`, `{unit}` and `{battery}` in the
+    /// given template.
+    fn format(&self, template: &str, name: &str, unit: &str) -> String {
+        let max = self
+            .probe_temperatures
+            .iter()
+            .copied()
+            .reduce(f32::max)
+            .map_or_else(|| "-".to_owned(), format_whole_degrees);
+        let battery = self
+            .battery_percentage
+            .map_or_else(|| "-".to_owned(), |battery| battery.to_string());
+        template
+            .replace("{name}", name)
+            .replace("{probes}", &self.probe_temperatures.len().to_string())
+            .replace("{max}", &max)
+            .replace("{unit}", unit)
+            .replace("{battery}", &battery)
+    }
+}
+
+/// Format a temperature rounded to a whole number of degrees, without showing "-0" for small
+/// negative temperatures.
+fn format_whole_degrees(temperature: f32) -> String {
+    let rounded = temperature.round();
+    if rounded == 0.0 {
+        "0".to_owned()
+    } else {
+        format!("{:.0}", rounded)
+    }
+}
+
+/// The target temperatures set for each probe.
+#[derive(Debug, Default)]
+struct TargetState {
+    /// Map from probe index to target settings.
+    targets: HashMap<u8, Arc<AsyncMutex<Target>>>,
+}
+
+impl TargetState {
+    /// Load the targets saved for the given device, if saving them is configured.
+    fn load(config: &Config, mac_address: MacAddress) -> Self {
+        let targets: HashMap<u8, Target> = match &config.bbq.state_file {
+            Some(state_file) => load_state(state_file, mac_address),
+            None => HashMap::new(),
+        };
+        Self {
+            targets: targets
+                .into_iter()
+                .map(|(probe_index, target)| (probe_index, Arc::new(AsyncMutex::new(target))))
+                .collect(),
+        }
+    }
+
+    fn target(&mut self, probe_index: u8) -> Arc<AsyncMutex<Target>> {
+        self.targets.entry(probe_index).or_default().clone()
+    }
+
+    /// Get the targets of all probes which have one, with their probe indices.
+    fn all(&self) -> Vec<(u8, Arc<AsyncMutex<Target>>)> {
+        self.targets
+            .iter()
+            .map(|(&probe_index, target)| (probe_index, target.clone()))
+            .collect()
+    }
+}
+
+/// The target mode and temperature for a single probe.
+#[derive(Clone, Default, Debug, Deserialize, PartialEq, Serialize)]
+struct Target {
+    mode: TargetMode,
+    temperature_min: f32,
+    temperature_max: f32,
+}
+
+impl Target {
+    fn from_preset(preset: &TargetPreset) -> Self {
+        Self {
+            mode: match preset.mode {
+                PresetMode::Single => TargetMode::Single,
+                PresetMode::Range => TargetMode::Range,
+                PresetMode::Hold => TargetMode::Hold,
+            },
+            temperature_min: preset.min,
+            temperature_max: preset.max,
+        }
+    }
+
+    /// The values to publish for the temperature properties of the target in the given unit, using
+    /// `unset_target_value` for those which don't apply in its mode.
+    fn property_values(
+        &self,
+        unset_target_value: UnsetTargetValue,
+        unit: TemperatureUnit,
+        precision: usize,
+    ) -> [(&'static str, String); 3] {
+        let value = |applies: bool, temperature: f32| {
+            if applies || unset_target_value == UnsetTargetValue::Default {
+                format_temperature(temperature, unit, precision)
+            } else {
+                String::new()
+            }
+        };
+        let range = matches!(self.mode, TargetMode::Range | TargetMode::Hold);
+        [
+            (
+                PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+                value(range, self.temperature_min),
+            ),
+            (
+                PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+                value(self.mode != TargetMode::None, self.temperature_max),
+            ),
+            // The setpoint is only meaningful for a single target temperature.
+            (
+                PROPERTY_ID_SETPOINT,
+                value(self.mode == TargetMode::Single, self.temperature_max),
+            ),
+        ]
+    }
+
+    /// Whether the minimum is below the maximum, if the mode uses a range.
+    fn range_valid(&self) -> bool {
+        match self.mode {
+            TargetMode::None | TargetMode::Single => true,
+            TargetMode::Range | TargetMode::Hold => self.temperature_min < self.temperature_max,
+        }
+    }
+
+    /// Whether the given temperature has reached the target, i.e. gone above the maximum or below
+    /// the minimum as appropriate for the mode. `comparison` determines whether a temperature
+    /// exactly equal to the target counts.
+    fn reached(&self, temperature: f32, comparison: TargetComparison) -> bool {
+        let (above, below) = match comparison {
+            TargetComparison::Inclusive => (
+                temperature >= self.temperature_max,
+                temperature <= self.temperature_min,
+            ),
+            TargetComparison::Exclusive => (
+                temperature > self.temperature_max,
+                temperature < self.temperature_min,
+            ),
+        };
+        match self.mode {
+            TargetMode::None => false,
+            TargetMode::Single => above,
+            TargetMode::Range | TargetMode::Hold => above || below,
+        }
+    }
+}
+
+/// A change to the whole target of a probe at once, as set on the `set_target` property. The
+/// temperatures are in the published unit, and those which are left out keep their current values.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct TargetCommand {
+    mode: String,
+    min: Option<f32>,
+    max: Option<f32>,
+}
+
+impl TargetCommand {
+    /// Apply the command to the given target, returning the new target.
+    fn apply(&self, target: &Target, unit: TemperatureUnit) -> Result<Target, Report> {
+        let mode = self.mode.parse()?;
+        if mode == TargetMode::None {
+            return Ok(Target::default());
+        }
+        Ok(Target {
+            mode,
+            temperature_min: self
+                .min
+                .map_or(target.temperature_min, |min| to_celsius(min, unit)),
+            temperature_max: self
+                .max
+                .map_or(target.temperature_max, |max| to_celsius(max, unit)),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+enum TargetMode {
+    #[default]
+    None,
+    Single,
+    Range,
+    /// Like `Range`, but the alarm is re-armed whenever it is silenced, so it keeps sounding for as
+    /// long as the temperature is outside the range.
+    Hold,
+}
+
+impl FromStr for TargetMode {
+    type Err = Report;
+
+    /// Parse a target mode, ignoring case and accepting some short aliases as well as the names
+    /// published for the enumeration property.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mode = s.trim().to_lowercase();
+        if mode == TARGET_MODE_SINGLE.to_lowercase() {
+            return Ok(Self::Single);
+        }
+        match mode.as_str() {
+            "none" | "off" => Ok(Self::None),
+            "single" | "max" | "maximum" => Ok(Self::Single),
+            "range" => Ok(Self::Range),
+            "hold" => Ok(Self::Hold),
+            _ => bail!("Invalid target mode {}", s),
+        }
+    }
+}
+
+impl TargetMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => TARGET_MODE_NONE,
+            Self::Single => TARGET_MODE_SINGLE,
+            Self::Range => TARGET_MODE_RANGE,
+            Self::Hold => TARGET_MODE_HOLD,
+        }
+    }
+}
+
+impl Display for TargetMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Tracks how fast the temperature of a probe is changing.
+#[derive(Clone, Debug, Default)]
+struct TemperatureTrend {
+    /// The previous temperature in ºC and when it was read.
+    last_reading: Option<(f32, Instant)>,
+    /// The smoothed rate of change in ºC per minute.
+    rate: Option<f32>,
+}
+
+impl TemperatureTrend {
+    /// Record a new temperature reading in ºC, and return the updated rate of change in ºC per
+    /// minute, if there have been enough readings to tell.
+    fn record(&mut self, temperature: f32, now: Instant) -> Option<f32> {
+        if let Some((last_temperature, last_time)) = self.last_reading {
+            let minutes = (now - last_time).as_secs_f32() / 60.0;
+            if minutes <= 0.0 {
+                return self.rate;
+            }
+            let rate = (temperature - last_temperature) / minutes;
+            // Use an exponential moving average, so that a single noisy reading doesn't change the
+            // rate much.
+            self.rate = Some(match self.rate {
+                Some(previous) => previous + RATE_SMOOTHING * (rate - previous),
+                None => rate,
+            });
+        }
+        self.last_reading = Some((temperature, now));
+        self.rate
+    }
+}
+
+/// Estimate how long until a probe reaches the maximum of its target, by linear extrapolation from
+/// its current temperature at its recent rate of change in ºC per minute. This doesn't account for
+/// the stall or any other change in the rate, so is only a rough guide.
+///
+/// Returns `None` if there is no target or no rate yet, the temperature isn't rising by at least
+/// `MIN_RISING_RATE`, or the estimate is too long to represent. If the target has already been
+/// reached then the estimate is zero.
+fn estimate_time_to_target(
+    temperature: f32,
+    target: &Target,
+    rate: Option<f32>,
+) -> Option<Duration> {
+    if target.mode == TargetMode::None {
+        return None;
+    }
+    if temperature >= target.temperature_max {
+        return Some(Duration::ZERO);
+    }
+    let rate = rate.filter(|&rate| rate >= MIN_RISING_RATE)?;
+    let minutes = (target.temperature_max - temperature) / rate;
+    Duration::try_from_secs_f32(minutes * 60.0).ok()
+}
+
+/// A cooking timer, which counts up from when it is started until it is stopped.
+#[derive(Clone, Debug, Default)]
+struct CookTimer {
+    /// When the timer was started, if it is running.
+    started: Option<Instant>,
+    /// How long it ran for before it was last stopped.
+    stopped_elapsed: Duration,
+}
+
+impl CookTimer {
+    /// Start the timer from zero, unless it is already running.
+    fn start(&mut self, now: Instant) {
+        if self.started.is_none() {
+            self.started = Some(now);
+            self.stopped_elapsed = Duration::ZERO;
+        }
+    }
+
+    /// Stop the timer, freezing its elapsed time.
+    fn stop(&mut self, now: Instant) {
+        self.stopped_elapsed = self.elapsed(now);
+        self.started = None;
+    }
+
+    fn is_running(&self) -> bool {
+        self.started.is_some()
+    }
+
+    fn elapsed(&self, now: Instant) -> Duration {
+        match self.started {
+            Some(started) => now - started,
+            None => self.stopped_elapsed,
+        }
+    }
+}
+
+/// Whether a probe's temperature should be published again, given the temperature last published
+/// and when. It is due if the minimum interval has passed since then or it has changed by more than
+/// the configured delta.
+fn temperature_publish_due(
+    last_publish: Option<&(f32, Instant)>,
+    temperature: f32,
+    now: Instant,
+    config: &BbqConfig,
+) -> bool {
+    let (last_temperature, last_time) = match last_publish {
+        Some(last_publish) => *last_publish,
+        None => return true,
+    };
+    now - last_time >= Duration::from_secs(config.min_publish_interval_seconds)
+        || (temperature - last_temperature).abs() > config.publish_delta
+}
+
+/// Whether a probe's node should be removed, given how many readings in a row it has been missing
+/// from and since when.
+fn probe_removal_due(missing: (u32, Instant), now: Instant, config: &BbqConfig) -> bool {
+    let (count, since) = missing;
+    count >= config.probe_removal_readings
+        && now - since >= Duration::from_secs(config.probe_removal_seconds)
+}
+
+/// Add `temperature` to the recent readings of a probe, and return the average of the last `window`
+/// of them. A window of 0 or 1 leaves the temperature unchanged.
+fn smooth(recent_readings: &mut VecDeque<f32>, window: usize, temperature: f32) -> f32 {
+    recent_readings.push_back(temperature);
+    while recent_readings.len() > window.max(1) {
+        recent_readings.pop_front();
+    }
+    recent_readings.iter().sum::<f32>() / recent_readings.len() as f32
+}
+
+/// The lowest and highest temperatures in ºC recorded by a probe since it was connected or they were
+/// reset.
+#[derive(Clone, Debug, PartialEq)]
+struct RecordedTemperatures {
+    min: f32,
+    max: f32,
+}
+
+impl RecordedTemperatures {
+    fn new(temperature: f32) -> Self {
+        Self {
+            min: temperature,
+            max: temperature,
+        }
+    }
+
+    /// Record a new temperature, and return whether it changed the minimum or maximum.
+    fn record(&mut self, temperature: f32) -> bool {
+        let previous = self.clone();
+        self.min = self.min.min(temperature);
+        self.max = self.max.max(temperature);
+        *self != previous
+    }
+}
+
+/// Whether a probe's temperature is outside the alarm thresholds configured for it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+enum AlarmCondition {
+    #[default]
+    Normal,
+    Low,
+    High,
+}
+
+impl AlarmCondition {
+    /// Check the given temperature against the thresholds for its probe, if there are any.
+    fn new(temperature: f32, thresholds: Option<&ProbeAlarmConfig>) -> Self {
+        let thresholds = match thresholds {
+            Some(thresholds) => thresholds,
+            None => return Self::Normal,
+        };
+        if matches!(thresholds.alarm_low, Some(low) if temperature < low) {
+            Self::Low
+        } else if matches!(thresholds.alarm_high, Some(high) if temperature > high) {
+            Self::High
+        } else {
+            Self::Normal
+        }
+    }
+}
+
+impl Display for AlarmCondition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Normal => ALARM_CONDITION_NORMAL,
+            Self::Low => ALARM_CONDITION_LOW,
+            Self::High => ALARM_CONDITION_HIGH,
+        })
+    }
+}
+
+/// Calculate the battery percentage from the voltage, mapping linearly from the configured minimum
+/// voltage (or 0) to the configured maximum voltage (or the maximum reported by the device).
+fn battery_percentage(current_voltage: u16, max_voltage: u16, device_config: &DeviceConfig) -> u32 {
+    if !device_config.battery_curve.is_empty() {
+        return battery_curve_percentage(current_voltage, &device_config.battery_curve);
+    }
+    let min_voltage = u32::from(device_config.battery_voltage_min.unwrap_or(0));
+    let max_voltage = u32::from(device_config.battery_voltage_max.unwrap_or(max_voltage));
+    if max_voltage <= min_voltage {
+        return 0;
+    }
+    let current_voltage = u32::from(current_voltage).clamp(min_voltage, max_voltage);
+    (current_voltage - min_voltage) * 100 / (max_voltage - min_voltage)
+}
+
+/// Look up the battery percentage for the given voltage on a curve, interpolating linearly between
+/// the points either side of it. Voltages beyond either end of the curve get the percentage at that
+/// end. The curve must not be empty, and must be in increasing order of voltage.
+fn battery_curve_percentage(current_voltage: u16, curve: &[BatteryCurvePoint]) -> u32 {
+    let first = curve[0];
+    if current_voltage <= first.voltage {
+        return first.percentage;
+    }
+    for pair in curve.windows(2) {
+        let (low, high) = (pair[0], pair[1]);
+        if current_voltage <= high.voltage {
+            let fraction =
+                f32::from(current_voltage - low.voltage) / f32::from(high.voltage - low.voltage);
+            let percentage =
+                low.percentage as f32 + fraction * (high.percentage as f32 - low.percentage as f32);
+            return percentage.round() as u32;
+        }
+    }
+    curve[curve.len() - 1].percentage
+}
+
+/// The mean of the given temperatures, or `None` if there are none.
+fn average(temperatures: &[f32]) -> Option<f32> {
+    if temperatures.is_empty() {
+        None
+    } else {
+        Some(temperatures.iter().sum::<f32>() / temperatures.len() as f32)
+    }
+}
+
+fn probe_id_to_index(probe_id: &str) -> Option<u8> {
+    probe_id.strip_prefix(NODE_ID_PROBE_PREFIX)?.parse().ok()
+}
+
+/// Connect to the given device and authenticate with it, trying the given custom authenticator
+/// before the default one.
+async fn connect_and_authenticate(
+    session: &BluetoothSession,
+    device: &DeviceInfo,
+    authenticator: Option<&dyn Authenticator>,
+) -> Result<BBQDevice, Report> {
+    session.connect(&device.id).await?;
+    let connected_device = BBQDevice::new(session.clone(), device.id.clone()).await?;
+    authenticate(session, &device.id, &connected_device, authenticator).await?;
+    Ok(connected_device)
+}
+
+/// Authenticate with the given connected device, trying the custom authenticator first if there is
+/// one and falling back to the default handshake.
+async fn authenticate(
+    session: &BluetoothSession,
+    device_id: &DeviceId,
+    device: &BBQDevice,
+    authenticator: Option<&dyn Authenticator>,
+) -> Result<(), Report> {
+    log::info!("Authenticating...");
+    let authenticated = if let Some(authenticator) = authenticator {
+        match authenticator.authenticate(session, device_id, device).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!(
+                    "Custom authentication failed, falling back to default: {}",
+                    e
+                );
+                false
+            }
+        }
+    } else {
+        false
+    };
+    if !authenticated {
+        device.authenticate().await?;
+    }
+    log::info!("Authenticated.");
+    Ok(())
+}
+
+/// Follow the items of the given stream with `None` when it ends, so that its ending can be handled
+/// straight away.
+fn with_end<T: Send + 'static>(
+    stream: BoxStream<'static, T>,
+) -> stream::Fuse<BoxStream<'static, Option<T>>> {
+    stream
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .boxed()
+        .fuse()
+}
+
+/// Choose the name to use for a device: the configured name if there is one, otherwise the Bluetooth
+/// device name. BlueZ sometimes doesn't know the name yet just after discovery, in which case the MAC
+/// address is used instead.
+fn device_name(
+    configured_name: Option<String>,
+    bluetooth_name: Option<String>,
+    mac_address: MacAddress,
+) -> String {
+    configured_name
+        .or(bluetooth_name)
+        .unwrap_or_else(|| format!("BBQ {}", mac_address))
+}
+
+/// Get the calibration offset for the given probe from `probe_offsets`, or 0 if there is none.
+fn probe_offset(probe_offsets: &[f32], probe_index: u8) -> f32 {
+    probe_offsets
+        .get(probe_index as usize)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// The unit in which to publish temperatures, which is the current display unit if
+/// `publish_display_unit` is set or else Celsius.
+fn publish_unit(
+    publish_display_unit: bool,
+    current_unit: &Mutex<TemperatureUnit>,
+) -> TemperatureUnit {
+    if publish_display_unit {
+        *current_unit.lock().unwrap()
+    } else {
+        TemperatureUnit::Celcius
+    }
+}
+
+/// Get the value retained by the MQTT broker for the given topic, if there is one.
+async fn read_retained(mqtt_options: MqttOptions, topic: &str) -> Result<Option<String>, Report> {
+    let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+    client.subscribe(topic, QoS::AtLeastOnce).await?;
+    let subscribed = timeout(RETAINED_SUBSCRIBE_TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Incoming::SubAck(_)) = event_loop.poll().await? {
+                return Ok::<_, Report>(());
+            }
+        }
+    });
+    subscribed
+        .await
+        .wrap_err("Timed out subscribing to retained value")??;
+    // The broker sends any retained value straight after acknowledging the subscription.
+    let retained = timeout(RETAINED_VALUE_TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Incoming::Publish(publish)) = event_loop.poll().await? {
+                if publish.topic == topic {
+                    return Ok::<_, Report>(String::from_utf8(publish.payload.to_vec())?);
+                }
+            }
+        }
+    })
+    .await;
+    // Nothing is waiting for the disconnection to be sent, but the connection is dropped anyway.
+    let _ = client.try_disconnect();
+    match retained {
+        Ok(value) => Ok(Some(value?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The other temperature unit to the given one, and the ID of the probe property which is published
+/// in it if `publish_other_unit` is set.
+fn other_unit(unit: TemperatureUnit) -> (&'static str, TemperatureUnit) {
+    match unit {
+        TemperatureUnit::Celcius => (
+            PROPERTY_ID_TEMPERATURE_FAHRENHEIT,
+            TemperatureUnit::Fahrenheit,
+        ),
+        TemperatureUnit::Fahrenheit => (PROPERTY_ID_TEMPERATURE_CELSIUS, TemperatureUnit::Celcius),
+    }
+}
+
+/// Convert a temperature in Celsius to the given unit for publishing, rounded to hundredths of a
+/// degree to avoid floating point noise.
+fn display_temperature(temperature: f32, unit: TemperatureUnit) -> f32 {
+    (from_celsius(temperature, unit) * 100.0).round() / 100.0
+}
+
+/// Convert a temperature in Celsius to the given unit and format it with the given number of
+/// decimal places, for publishing.
+fn format_temperature(temperature: f32, unit: TemperatureUnit, precision: usize) -> String {
+    format!("{:.*}", precision, from_celsius(temperature, unit))
+}
+
+/// Convert a difference in temperature in Celsius to the given unit and format it with the given
+/// number of decimal places, for publishing.
+fn format_difference(difference: f32, unit: TemperatureUnit, precision: usize) -> String {
+    format!(
+        "{:.*}",
+        precision,
+        difference_from_celsius(difference, unit)
+    )
+}
+
+/// Parse a display unit, as one of the published `DISPLAY_UNITS` or the more convenient forms like
+/// "C" or "fahrenheit", ignoring case.
+pub fn parse_display_unit(value: &str) -> Option<TemperatureUnit> {
+    match value {
+        DISPLAY_UNIT_CELCIUS => return Some(TemperatureUnit::Celcius),
+        DISPLAY_UNIT_FAHRENHEIT => return Some(TemperatureUnit::Fahrenheit),
+        _ => {}
+    }
+    match value.trim().to_lowercase().as_str() {
+        "c" | "°c" | "ºc" | "celsius" | "celcius" => Some(TemperatureUnit::Celcius),
+        "f" | "°f" | "ºf" | "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+        _ => None,
+    }
+}
+
+pub fn display_unit(unit: TemperatureUnit) -> &'static str {
+    match unit {
+        TemperatureUnit::Celcius => DISPLAY_UNIT_CELCIUS,
+        TemperatureUnit::Fahrenheit => DISPLAY_UNIT_FAHRENHEIT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicBool, AtomicU32};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+    use tokio::task::yield_now;
+
+    /// A minimal MQTT 3.1.1 broker which accepts a single client, acknowledges everything it sends,
+    /// and returns the topics which it published to once it disconnects. The given retained values
+    /// are sent to the client when it subscribes to exactly their topic.
+    async fn fake_broker(listener: TcpListener, retained: &[(&str, &str)]) -> Vec<String> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut topics = vec![];
+        loop {
+            let mut header = [0; 1];
+            if stream.read_exact(&mut header).await.is_err() {
+                return topics;
+            }
+            let mut length = 0;
+            let mut shift = 0;
+            loop {
+                let byte = stream.read_u8().await.unwrap();
+                length |= usize::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+            let response = match header[0] >> 4 {
+                // CONNECT
+                1 => vec![0x20, 2, 0, 0],
+                // PUBLISH
+                3 => {
+                    let topic_length = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    topics.push(String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap());
+                    if header[0] & 0x06 == 0 {
+                        continue;
+                    }
+                    vec![0x40, 2, body[2 + topic_length], body[3 + topic_length]]
+                }
+                // SUBSCRIBE
+                8 => {
+                    let mut filters = vec![];
+                    let mut i = 2;
+                    while i < body.len() {
+                        let filter_length = usize::from(u16::from_be_bytes([body[i], body[i + 1]]));
+                        filters.push(
+                            String::from_utf8(body[i + 2..i + 2 + filter_length].to_vec()).unwrap(),
+                        );
+                        i += 2 + filter_length + 1;
+                    }
+                    let mut response = vec![0x90, 2 + filters.len() as u8, body[0], body[1]];
+                    response.extend(vec![1; filters.len()]);
+                    for (topic, value) in retained {
+                        if filters.iter().any(|filter| filter == topic) {
+                            response.extend([0x31, (2 + topic.len() + value.len()) as u8, 0]);
+                            response.push(topic.len() as u8);
+                            response.extend(topic.as_bytes());
+                            response.extend(value.as_bytes());
+                        }
+                    }
+                    response
+                }
+                // PINGREQ
+                12 => vec![0xd0, 0],
+                // DISCONNECT
+                14 => return topics,
+                _ => continue,
+            };
+            stream.write_all(&response).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn retained_value() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = tokio::spawn(fake_broker(
+            listener,
+            &[("homie/cloudbbq-020000000001/settings/unit", "ºF")],
+        ));
+        let mqtt_options = MqttOptions::new("test", "127.0.0.1", port);
+        assert_eq!(
+            read_retained(mqtt_options, "homie/cloudbbq-020000000001/settings/unit")
+                .await
+                .unwrap(),
+            Some("ºF".to_owned())
+        );
+        broker.await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = tokio::spawn(fake_broker(listener, &[]));
+        let mqtt_options = MqttOptions::new("test", "127.0.0.1", port);
+        assert_eq!(
+            read_retained(mqtt_options, "homie/cloudbbq-020000000001/settings/unit")
+                .await
+                .unwrap(),
+            None
+        );
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn simulated_device_topics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mqtt.host = "127.0.0.1".to_owned();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.simulate.probes = 2;
+        config.bbq.restore_display_unit = false;
+        let broker = tokio::spawn(fake_broker(listener, &[]));
+        let (_bridge_commands_tx, bridge_commands) = broadcast::channel(1);
+
+        Bbq::simulated(1, config)
+            .run(None, bridge_commands, None, None, None, true)
+            .await
+            .unwrap();
+
+        let topics = broker.await.unwrap();
+        for topic in [
+            "homie/cloudbbq-020000000001/$state",
+            "homie/cloudbbq-020000000001/$nodes",
+            // homie-device publishes these itself, with a fixed interval.
+            "homie/cloudbbq-020000000001/$stats/interval",
+            "homie/cloudbbq-020000000001/$stats/uptime",
+            "homie/cloudbbq-020000000001/battery/percentage",
+            "homie/cloudbbq-020000000001/info/bluetooth_status",
+            "homie/cloudbbq-020000000001/probe0/temperature",
+            "homie/cloudbbq-020000000001/probe1/temperature",
+        ] {
+            assert!(topics.iter().any(|t| t == topic), "{} not published", topic);
+        }
+    }
+
+    #[test]
+    fn battery_percentage_default() {
+        let device_config = DeviceConfig::default();
+        assert_eq!(battery_percentage(6000, 6000, &device_config), 100);
+        assert_eq!(battery_percentage(4500, 6000, &device_config), 75);
+        assert_eq!(battery_percentage(0, 6000, &device_config), 0);
+        assert_eq!(battery_percentage(0, 0, &device_config), 0);
+    }
+
+    #[test]
+    fn battery_percentage_calibrated() {
+        let device_config = DeviceConfig {
+            battery_voltage_min: Some(3000),
+            battery_voltage_max: Some(4000),
+            ..Default::default()
+        };
+        assert_eq!(battery_percentage(4200, 6000, &device_config), 100);
+        assert_eq!(battery_percentage(3500, 6000, &device_config), 50);
+        assert_eq!(battery_percentage(2000, 6000, &device_config), 0);
+    }
+
+    #[test]
+    fn battery_percentage_curve() {
+        let device_config = DeviceConfig {
+            battery_curve: vec![
+                BatteryCurvePoint {
+                    voltage: 3300,
+                    percentage: 0,
+                },
+                BatteryCurvePoint {
+                    voltage: 3700,
+                    percentage: 20,
+                },
+                BatteryCurvePoint {
+                    voltage: 4200,
+                    percentage: 100,
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(battery_percentage(3000, 6000, &device_config), 0);
+        assert_eq!(battery_percentage(3500, 6000, &device_config), 10);
+        assert_eq!(battery_percentage(3700, 6000, &device_config), 20);
+        assert_eq!(battery_percentage(3950, 6000, &device_config), 60);
+        assert_eq!(battery_percentage(4300, 6000, &device_config), 100);
+    }
+
+    #[test]
+    fn temperature_trend() {
+        let start = Instant::now();
+        let mut trend = TemperatureTrend::default();
+        assert_eq!(trend.record(20.0, start), None);
+        assert_eq!(
+            trend.record(20.5, start + Duration::from_secs(30)),
+            Some(1.0)
+        );
+        // A jump is smoothed.
+        assert_eq!(
+            trend.record(23.5, start + Duration::from_secs(60)),
+            Some(2.0)
+        );
+        // A reading at the same instant doesn't change anything.
+        assert_eq!(
+            trend.record(30.0, start + Duration::from_secs(60)),
+            Some(2.0)
+        );
+    }
+
+    #[test]
+    fn time_to_target() {
+        let target = Target {
+            mode: TargetMode::Single,
+            temperature_min: 0.0,
+            temperature_max: 90.0,
+        };
+        assert_eq!(
+            estimate_time_to_target(60.0, &target, Some(0.5)),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            estimate_time_to_target(91.0, &target, Some(-0.5)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(estimate_time_to_target(60.0, &target, Some(0.0)), None);
+        assert_eq!(estimate_time_to_target(60.0, &target, Some(-1.0)), None);
+        // A rate which has decayed towards zero while the temperature was flat.
+        assert_eq!(
+            estimate_time_to_target(60.0, &target, Some(f32::MIN_POSITIVE)),
+            None
+        );
+        assert_eq!(estimate_time_to_target(60.0, &target, Some(1e-40)), None);
+        assert_eq!(estimate_time_to_target(-1e30, &target, Some(0.01)), None);
+        assert_eq!(estimate_time_to_target(60.0, &target, None), None);
+        assert_eq!(
+            estimate_time_to_target(60.0, &Target::default(), Some(0.5)),
+            None
+        );
+    }
+
+    #[test]
+    fn cook_timer() {
+        let start = Instant::now();
+        let mut cook_timer = CookTimer::default();
+        assert!(!cook_timer.is_running());
+        assert_eq!(cook_timer.elapsed(start), Duration::ZERO);
+
+        cook_timer.start(start);
+        // Starting again while running doesn't reset it.
+        cook_timer.start(start + Duration::from_secs(5));
+        assert!(cook_timer.is_running());
+        assert_eq!(
+            cook_timer.elapsed(start + Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+
+        cook_timer.stop(start + Duration::from_secs(20));
+        assert!(!cook_timer.is_running());
+        assert_eq!(
+            cook_timer.elapsed(start + Duration::from_secs(30)),
+            Duration::from_secs(20)
+        );
+
+        cook_timer.start(start + Duration::from_secs(40));
+        assert_eq!(
+            cook_timer.elapsed(start + Duration::from_secs(41)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn temperature_publish_rate_limit() {
+        let config = BbqConfig {
+            min_publish_interval_seconds: 10,
+            publish_delta: 0.5,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let last_publish = (20.0, start);
+        assert!(temperature_publish_due(None, 20.0, start, &config));
+        assert!(!temperature_publish_due(
+            Some(&last_publish),
+            20.5,
+            start + Duration::from_secs(5),
+            &config
+        ));
+        assert!(temperature_publish_due(
+            Some(&last_publish),
+            19.0,
+            start + Duration::from_secs(5),
+            &config
+        ));
+        assert!(temperature_publish_due(
+            Some(&last_publish),
+            20.0,
+            start + Duration::from_secs(10),
+            &config
+        ));
+        // With the default config every reading is published.
+        assert!(temperature_publish_due(
+            Some(&last_publish),
+            20.0,
+            start,
+            &BbqConfig::default()
+        ));
+    }
+
+    #[test]
+    fn probe_removal_grace_period() {
+        let start = Instant::now();
+        // By default a probe is removed as soon as it is missing.
+        assert!(probe_removal_due((1, start), start, &BbqConfig::default()));
+
+        let config = BbqConfig {
+            probe_removal_readings: 3,
+            probe_removal_seconds: 5,
+            ..Default::default()
+        };
+        assert!(!probe_removal_due(
+            (2, start),
+            start + Duration::from_secs(10),
+            &config
+        ));
+        assert!(!probe_removal_due(
+            (3, start),
+            start + Duration::from_secs(4),
+            &config
+        ));
+        assert!(probe_removal_due(
+            (3, start),
+            start + Duration::from_secs(5),
+            &config
+        ));
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut recent_readings = VecDeque::new();
+        assert_eq!(smooth(&mut recent_readings, 1, 20.0), 20.0);
+        assert_eq!(smooth(&mut recent_readings, 1, 22.0), 22.0);
+        assert_eq!(smooth(&mut recent_readings, 3, 26.0), 24.0);
+        assert_eq!(smooth(&mut recent_readings, 3, 30.0), 26.0);
+        assert_eq!(smooth(&mut recent_readings, 3, 22.0), 26.0);
+
+        let mut recent_readings = VecDeque::new();
+        assert_eq!(smooth(&mut recent_readings, 0, 20.0), 20.0);
+        assert_eq!(smooth(&mut recent_readings, 0, 21.0), 21.0);
+    }
+
+    #[test]
+    fn recorded_temperatures() {
+        let mut recorded = RecordedTemperatures::new(20.0);
+        assert!(recorded.record(25.5));
+        assert!(recorded.record(18.0));
+        assert!(!recorded.record(22.0));
+        assert!(!recorded.record(25.5));
+        assert_eq!(
+            recorded,
+            RecordedTemperatures {
+                min: 18.0,
+                max: 25.5
+            }
+        );
+    }
+
+    #[test]
+    fn alarm_condition() {
+        let thresholds = ProbeAlarmConfig {
+            alarm_low: Some(100.0),
+            alarm_high: Some(130.0),
+        };
+        assert_eq!(AlarmCondition::new(90.0, None), AlarmCondition::Normal);
+        assert_eq!(
+            AlarmCondition::new(99.5, Some(&thresholds)),
+            AlarmCondition::Low
+        );
+        assert_eq!(
+            AlarmCondition::new(100.0, Some(&thresholds)),
+            AlarmCondition::Normal
+        );
+        assert_eq!(
+            AlarmCondition::new(131.0, Some(&thresholds)),
+            AlarmCondition::High
+        );
+        let high_only = ProbeAlarmConfig {
+            alarm_high: Some(130.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            AlarmCondition::new(-10.0, Some(&high_only)),
+            AlarmCondition::Normal
+        );
+    }
+
+    #[test]
+    fn device_name_fallback() {
+        let mac_address: MacAddress = "00:11:22:aa:bb:cc".parse().unwrap();
+        assert_eq!(
+            device_name(
+                Some("Configured".to_owned()),
+                Some("iBBQ".to_owned()),
+                mac_address
+            ),
+            "Configured"
+        );
+        assert_eq!(
+            device_name(None, Some("iBBQ".to_owned()), mac_address),
+            "iBBQ"
+        );
+        assert_eq!(
+            device_name(None, None, mac_address),
+            "BBQ 00:11:22:AA:BB:CC"
+        );
+    }
+
+    #[test]
+    fn average_temperature() {
+        assert_eq!(average(&[]), None);
+        assert_eq!(average(&[60.0]), Some(60.0));
+        assert_eq!(average(&[55.0, 65.0, -30.0]), Some(30.0));
+    }
+
+    #[test]
+    fn display_unit_round_trip() {
+        for unit in DISPLAY_UNITS {
+            assert_eq!(display_unit(parse_display_unit(unit).unwrap()), unit);
+        }
+        assert_eq!(parse_display_unit("K"), None);
+    }
+
+    #[test]
+    fn parse_short_display_units() {
+        for unit in ["C", "c", "°C", "Celsius", "CELSIUS"] {
+            assert_eq!(parse_display_unit(unit), Some(TemperatureUnit::Celcius));
+        }
+        for unit in ["F", "f", "°F", "Fahrenheit", "fahrenheit"] {
+            assert_eq!(parse_display_unit(unit), Some(TemperatureUnit::Fahrenheit));
+        }
+        assert_eq!(parse_display_unit(""), None);
+        assert_eq!(parse_display_unit("Kelvin"), None);
+    }
+
+    #[test]
+    fn temperature_precision() {
+        assert_eq!(
+            format_temperature(23.45678, TemperatureUnit::Celcius, 1),
+            "23.5"
+        );
+        assert_eq!(
+            format_temperature(23.45678, TemperatureUnit::Celcius, 3),
+            "23.457"
+        );
+        assert_eq!(
+            format_temperature(100.0, TemperatureUnit::Fahrenheit, 0),
+            "212"
+        );
+        assert_eq!(
+            format_difference(1.2345, TemperatureUnit::Celcius, 2),
+            "1.23"
+        );
+        assert_eq!(
+            format_difference(10.0, TemperatureUnit::Fahrenheit, 1),
+            "18.0"
+        );
+    }
+
+    #[test]
+    fn other_units() {
+        let (property_id, unit) = other_unit(TemperatureUnit::Celcius);
+        assert_eq!(property_id, PROPERTY_ID_TEMPERATURE_FAHRENHEIT);
+        assert_eq!(display_temperature(100.0, unit), 212.0);
+        let (property_id, unit) = other_unit(TemperatureUnit::Fahrenheit);
+        assert_eq!(property_id, PROPERTY_ID_TEMPERATURE_CELSIUS);
+        assert_eq!(display_temperature(100.0, unit), 100.0);
+    }
+
+    #[test]
+    fn stop_result() {
+        assert!(Stop::Shutdown.into_result("Grill").is_ok());
+        assert!(Stop::Finished.into_result("Grill").is_ok());
+        assert_eq!(
+            Stop::StreamsEnded
+                .into_result("Grill")
+                .unwrap_err()
+                .to_string(),
+            "Data from Grill stopped unexpectedly"
+        );
+        assert!(Stop::Failed(eyre::eyre!("Oops"))
+            .into_result("Grill")
+            .is_err());
+    }
+
+    #[test]
+    fn self_test() {
+        let mut initial_data = InitialData::default();
+        assert_eq!(
+            self_test_result(&initial_data, false),
+            "failed: no real-time data, no battery level, setting target failed"
+        );
+        initial_data.real_time = true;
+        assert_eq!(
+            self_test_result(&initial_data, true),
+            "failed: no battery level"
+        );
+        initial_data.battery = true;
+        assert_eq!(self_test_result(&initial_data, true), SELF_TEST_PASSED);
+    }
+
+    #[test]
+    fn probe_port() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."02:00:00:00:00:01"]
+            probe_ports = [2, 1]
+            "#,
+        )
+        .unwrap();
+        let bbq = Bbq::simulated(1, config);
+        assert_eq!(bbq.probe_port(0), 2);
+        assert_eq!(bbq.probe_port(1), 1);
+        assert_eq!(bbq.probe_port(2), 3);
+    }
+
+    #[test]
+    fn packet_rate() {
+        let start = Instant::now();
+        let mut packet_rate = PacketRate::default();
+        packet_rate.record(start);
+        assert_eq!(packet_rate.rate(), None);
+        for i in 1..=10 {
+            packet_rate.record(start + Duration::from_millis(500 * i));
+        }
+        assert_eq!(packet_rate.rate(), Some(2.0));
+        // Packets older than the window are forgotten.
+        packet_rate.record(start + PACKET_RATE_WINDOW + Duration::from_secs(5));
+        packet_rate.record(start + PACKET_RATE_WINDOW + Duration::from_secs(6));
+        assert_eq!(packet_rate.rate(), Some(1.0));
+    }
+
+    #[test]
+    fn capabilities_json() {
+        let mut capabilities = Capabilities::default();
+        assert_eq!(
+            serde_json::to_string(&capabilities).unwrap(),
+            r#"{"probes":0,"targets":null,"alarm":null,"unit":null,"battery":false,"alarm_tones":[]}"#
+        );
+        capabilities.probes = 4;
+        capabilities.targets = Some(true);
+        capabilities.unit = Some(false);
+        capabilities.battery = true;
+        capabilities.alarm_tones = &["Beep"];
+        capabilities.last_published = Some("ignored".to_owned());
+        assert_eq!(
+            serde_json::to_string(&capabilities).unwrap(),
+            r#"{"probes":4,"targets":true,"alarm":null,"unit":false,"battery":true,"alarm_tones":["Beep"]}"#
+        );
+    }
+
+    #[test]
+    fn summary_format() {
+        let mut summary = Summary::default();
+        let template = "{name}: {probes} probes, max {max}{unit}, battery {battery}%";
+        assert_eq!(
+            summary.format(template, "Grill", "ºC"),
+            "Grill: 0 probes, max -ºC, battery -%"
+        );
+        summary.probe_temperatures = vec![150.2, 178.4, 60.0];
+        summary.battery_percentage = Some(74);
+        assert_eq!(
+            summary.format(template, "Grill", "ºC"),
+            "Grill: 3 probes, max 178ºC, battery 74%"
+        );
+    }
+
+    #[test]
+    fn summary_format_negative() {
+        let summary = Summary {
+            probe_temperatures: vec![-18.3, -0.4],
+            ..Default::default()
+        };
+        assert_eq!(summary.format("{max}", "Freezer", "ºC"), "0");
+        let summary = Summary {
+            probe_temperatures: vec![-18.3, -20.0],
+            ..Default::default()
+        };
+        assert_eq!(summary.format("{max}", "Freezer", "ºC"), "-18");
+    }
+
+    #[test]
+    fn target_reached_single() {
+        let target = Target {
+            mode: TargetMode::Single,
+            temperature_min: 0.0,
+            temperature_max: 60.0,
+        };
+        assert!(!target.reached(59.9, TargetComparison::Inclusive));
+        assert!(target.reached(60.0, TargetComparison::Inclusive));
+        assert!(target.reached(60.1, TargetComparison::Inclusive));
+        assert!(!target.reached(59.9, TargetComparison::Exclusive));
+        assert!(!target.reached(60.0, TargetComparison::Exclusive));
+        assert!(target.reached(60.1, TargetComparison::Exclusive));
+    }
+
+    #[test]
+    fn target_reached_range() {
+        let target = Target {
+            mode: TargetMode::Range,
+            temperature_min: 50.0,
+            temperature_max: 60.0,
+        };
+        assert!(target.reached(49.9, TargetComparison::Inclusive));
+        assert!(target.reached(50.0, TargetComparison::Inclusive));
+        assert!(!target.reached(50.1, TargetComparison::Inclusive));
+        assert!(!target.reached(59.9, TargetComparison::Inclusive));
+        assert!(target.reached(60.0, TargetComparison::Inclusive));
+        assert!(target.reached(60.1, TargetComparison::Inclusive));
+        assert!(target.reached(49.9, TargetComparison::Exclusive));
+        assert!(!target.reached(50.0, TargetComparison::Exclusive));
+        assert!(!target.reached(60.0, TargetComparison::Exclusive));
+        assert!(target.reached(60.1, TargetComparison::Exclusive));
+    }
+
+    #[test]
+    fn target_reached_hold() {
+        let target = Target {
+            mode: TargetMode::Hold,
+            temperature_min: 3.0,
+            temperature_max: 5.0,
+        };
+        assert!(target.reached(2.9, TargetComparison::Inclusive));
+        assert!(!target.reached(4.0, TargetComparison::Inclusive));
+        assert!(target.reached(5.1, TargetComparison::Inclusive));
+        assert_eq!(
+            TARGET_MODE_HOLD.parse::<TargetMode>().unwrap(),
+            TargetMode::Hold
+        );
+    }
+
+    #[test]
+    fn target_reached_negative() {
+        let target = Target {
+            mode: TargetMode::Range,
+            temperature_min: -25.0,
+            temperature_max: -15.0,
+        };
+        assert!(target.reached(-25.5, TargetComparison::Inclusive));
+        assert!(!target.reached(-20.0, TargetComparison::Inclusive));
+        assert!(target.reached(-15.0, TargetComparison::Inclusive));
+        assert!(!target.reached(-15.0, TargetComparison::Exclusive));
+        assert!(target.reached(-14.9, TargetComparison::Exclusive));
+    }
+
+    #[tokio::test]
+    async fn negative_target_update() {
+        let target = AsyncMutex::new(Target::default());
+        let apply = |_| async { Ok(()) };
+        let celsius = TemperatureUnit::Celcius;
+        update_target(
+            &target,
+            PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+            "-25.5",
+            celsius,
+            apply,
+        )
+        .await;
+        update_target(
+            &target,
+            PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+            "-15",
+            celsius,
+            apply,
+        )
+        .await;
+        let updated = update_target(
+            &target,
+            PROPERTY_ID_TARGET_MODE,
+            TARGET_MODE_RANGE,
+            celsius,
+            apply,
+        )
+        .await;
+        assert_eq!(
+            updated,
+            Some(Target {
+                mode: TargetMode::Range,
+                temperature_min: -25.5,
+                temperature_max: -15.0,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn mode_none_clears_target() {
+        let target = AsyncMutex::new(Target {
+            mode: TargetMode::Range,
+            temperature_min: 10.0,
+            temperature_max: 20.0,
+        });
+        let apply = |_| async { Ok(()) };
+        assert_eq!(
+            update_target(
+                &target,
+                PROPERTY_ID_TARGET_MODE,
+                TARGET_MODE_NONE,
+                TemperatureUnit::Celcius,
+                apply
+            )
+            .await,
+            Some(Target::default())
+        );
+        assert_eq!(*target.lock().await, Target::default());
+    }
+
+    #[tokio::test]
+    async fn inverted_range_rejected() {
+        let original = Target {
+            mode: TargetMode::Range,
+            temperature_min: 10.0,
+            temperature_max: 20.0,
+        };
+        let target = AsyncMutex::new(original.clone());
+        let apply = |_| async { panic!("Invalid target should not be applied") };
+        let celsius = TemperatureUnit::Celcius;
+        for (property_id, value) in [
+            (PROPERTY_ID_TARGET_TEMPERATURE_MIN, "30"),
+            (PROPERTY_ID_TARGET_TEMPERATURE_MAX, "10"),
+        ] {
+            assert_eq!(
+                update_target(&target, property_id, value, celsius, apply).await,
+                None
+            );
+        }
+        assert_eq!(*target.lock().await, original);
+    }
+
+    #[test]
+    fn fahrenheit_target_properties() {
+        let mut config = Config::default();
+        config.bbq.publish_display_unit = true;
+        let bbq = Bbq::simulated(0, config);
+        *bbq.current_unit.lock().unwrap() = TemperatureUnit::Fahrenheit;
+        let node = bbq.node_for_probe("probe0", 0);
+        for property_id in [
+            PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+            PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+            PROPERTY_ID_SETPOINT,
+        ] {
+            let property = node
+                .properties
+                .iter()
+                .find(|property| property.id == property_id)
+                .unwrap();
+            assert_eq!(property.unit.as_deref(), Some(DISPLAY_UNIT_FAHRENHEIT));
+        }
+    }
+
+    #[tokio::test]
+    async fn fahrenheit_target_update() {
+        let target = AsyncMutex::new(Target::default());
+        let apply = |_| async { Ok(()) };
+        let fahrenheit = TemperatureUnit::Fahrenheit;
+        let updated = update_target(&target, PROPERTY_ID_SETPOINT, "212", fahrenheit, apply)
+            .await
+            .unwrap();
+        assert_eq!(updated.temperature_max, 100.0);
+        assert_eq!(
+            updated.property_values(UnsetTargetValue::Default, fahrenheit, 0)[2],
+            (PROPERTY_ID_SETPOINT, "212".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn target_command_update() {
+        let target = AsyncMutex::new(Target {
+            mode: TargetMode::Single,
+            temperature_min: 10.0,
+            temperature_max: 90.0,
+        });
+        let applied = Mutex::new(Vec::new());
+        let apply = |target| {
+            applied.lock().unwrap().push(target);
+            async { Ok(()) }
+        };
+        let celcius = TemperatureUnit::Celcius;
+        let command = r#"{"mode":"Range","min":60,"max":80}"#;
+        let updated = update_target(&target, PROPERTY_ID_TARGET_COMMAND, command, celcius, apply)
+            .await
+            .unwrap();
+        let expected = Target {
+            mode: TargetMode::Range,
+            temperature_min: 60.0,
+            temperature_max: 80.0,
+        };
+        assert_eq!(updated, expected);
+        // The whole target is applied to the device at once.
+        assert_eq!(*applied.lock().unwrap(), vec![expected.clone()]);
+
+        // Temperatures which are left out are kept.
+        let command = r#"{"mode":"Maximum only","max":70}"#;
+        let updated = update_target(
+            &target,
+            PROPERTY_ID_TARGET_COMMAND,
+            command,
+            celcius,
+            |_| async { Ok(()) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.temperature_min, 60.0);
+        assert_eq!(updated.temperature_max, 70.0);
+
+        // Invalid commands are rejected without changing the target.
+        for command in [
+            "not json",
+            r#"{"mode":"Sideways"}"#,
+            r#"{"mode":"Range","min":80,"max":60}"#,
+            r#"{"mode":"Range","temperature":60}"#,
+        ] {
+            assert_eq!(
+                update_target(
+                    &target,
+                    PROPERTY_ID_TARGET_COMMAND,
+                    command,
+                    celcius,
+                    |_| { async { Ok(()) } }
+                )
+                .await,
+                None
+            );
+        }
+        assert_eq!(target.lock().await.temperature_max, 70.0);
+    }
+
+    #[test]
+    fn target_property_values() {
+        let target = Target {
+            mode: TargetMode::Single,
+            temperature_min: 0.0,
+            temperature_max: 60.5,
+        };
+        assert_eq!(
+            target.property_values(UnsetTargetValue::Default, TemperatureUnit::Celcius, 1),
+            [
+                (PROPERTY_ID_TARGET_TEMPERATURE_MIN, "0.0".to_owned()),
+                (PROPERTY_ID_TARGET_TEMPERATURE_MAX, "60.5".to_owned()),
+                (PROPERTY_ID_SETPOINT, "60.5".to_owned()),
+            ]
+        );
+        assert_eq!(
+            target.property_values(UnsetTargetValue::Empty, TemperatureUnit::Celcius, 1),
+            [
+                (PROPERTY_ID_TARGET_TEMPERATURE_MIN, "".to_owned()),
+                (PROPERTY_ID_TARGET_TEMPERATURE_MAX, "60.5".to_owned()),
+                (PROPERTY_ID_SETPOINT, "60.5".to_owned()),
+            ]
+        );
+        let none = Target::default();
+        assert_eq!(
+            none.property_values(UnsetTargetValue::Empty, TemperatureUnit::Celcius, 1),
+            [
+                (PROPERTY_ID_TARGET_TEMPERATURE_MIN, "".to_owned()),
+                (PROPERTY_ID_TARGET_TEMPERATURE_MAX, "".to_owned()),
+                (PROPERTY_ID_SETPOINT, "".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_target_mode() {
+        for mode in [
+            TargetMode::None,
+            TargetMode::Single,
+            TargetMode::Range,
+            TargetMode::Hold,
+        ] {
+            assert_eq!(mode.to_string().parse::<TargetMode>().unwrap(), mode);
+        }
+        assert_eq!(
+            "maximum ONLY".parse::<TargetMode>().unwrap(),
+            TargetMode::Single
+        );
+        assert_eq!("single".parse::<TargetMode>().unwrap(), TargetMode::Single);
+        assert_eq!("Max".parse::<TargetMode>().unwrap(), TargetMode::Single);
+        assert_eq!("off".parse::<TargetMode>().unwrap(), TargetMode::None);
+        assert_eq!("NONE".parse::<TargetMode>().unwrap(), TargetMode::None);
+        assert_eq!("range".parse::<TargetMode>().unwrap(), TargetMode::Range);
+        assert!("sideways".parse::<TargetMode>().is_err());
+        assert!("".parse::<TargetMode>().is_err());
+    }
+
+    #[test]
+    fn target_reached_none() {
+        let target = Target::default();
+        assert!(!target.reached(0.0, TargetComparison::Inclusive));
+        assert!(!target.reached(0.0, TargetComparison::Exclusive));
+    }
+
+    /// An update handler for a simulated device, whose channels nothing is listening to.
+    fn test_update_handler() -> UpdateHandler<SimulatedDevice> {
+        update_handler_for(SimulatedDevice::new(Default::default()))
+    }
+
+    fn update_handler_for<D: Thermometer>(device: D) -> UpdateHandler<D> {
+        UpdateHandler {
+            device,
+            target_state: Default::default(),
+            capabilities: Default::default(),
+            current_unit: Arc::new(Mutex::new(TemperatureUnit::Celcius)),
+            target_updates: unbounded().0,
+            republish_requests: unbounded().0,
+            recorded_resets: unbounded().0,
+            timer_commands: unbounded().0,
+            unit_updates: None,
+            probe_offsets: vec![],
+            state_file: None,
+            resync_targets_on_unit_change: true,
+            target_presets: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_change_resyncs_targets() {
+        let (target_updates, mut target_updates_rx) = unbounded();
+        let update_handler = UpdateHandler {
+            target_updates,
+            ..test_update_handler()
+        };
+        let target = Target {
+            mode: TargetMode::Single,
+            temperature_min: 0.0,
+            temperature_max: 60.0,
+        };
+        let probe_target = update_handler.target_state.lock().await.target(2);
+        *probe_target.lock().await = target.clone();
+
+        update_handler
+            .set_unit(TemperatureUnit::Fahrenheit)
+            .await
+            .unwrap();
+
+        assert_eq!(target_updates_rx.try_next().unwrap(), Some((2, target)));
+        assert!(target_updates_rx.try_next().is_err());
+        assert_eq!(update_handler.capabilities.lock().unwrap().unit, Some(true));
+        assert_eq!(
+            *update_handler.current_unit.lock().unwrap(),
+            TemperatureUnit::Fahrenheit
+        );
+    }
+
+    #[tokio::test]
+    async fn republish_request() {
+        let (republish_requests, mut republish_requests_rx) = unbounded();
+        let update_handler = UpdateHandler {
+            republish_requests,
+            ..test_update_handler()
+        };
+
+        for _ in 0..2 {
+            assert_eq!(
+                update_handler
+                    .clone()
+                    .handle_update(
+                        NODE_ID_SETTINGS.to_owned(),
+                        PROPERTY_ID_REPUBLISH.to_owned(),
+                        "true".to_owned()
+                    )
+                    .await,
+                Some("true".to_owned())
+            );
+        }
+        update_handler
+            .clone()
+            .handle_update(
+                NODE_ID_SETTINGS.to_owned(),
+                PROPERTY_ID_REPUBLISH.to_owned(),
+                "false".to_owned(),
+            )
+            .await;
+
+        assert_eq!(republish_requests_rx.try_next().unwrap(), Some(()));
+        assert_eq!(republish_requests_rx.try_next().unwrap(), Some(()));
+        assert!(republish_requests_rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn reset_recorded_request() {
+        let (recorded_resets, mut recorded_resets_rx) = unbounded();
+        let update_handler = UpdateHandler {
+            recorded_resets,
+            ..test_update_handler()
+        };
+
+        assert_eq!(
+            update_handler
+                .handle_update(
+                    "probe1".to_owned(),
+                    PROPERTY_ID_RESET_RECORDED.to_owned(),
+                    "true".to_owned()
+                )
+                .await,
+            Some("true".to_owned())
+        );
+
+        assert_eq!(recorded_resets_rx.try_next().unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn sound_alarm_request() {
+        let update_handler = test_update_handler();
+        assert!(!update_handler.device.alarm_sounding());
+
+        assert_eq!(
+            update_handler
+                .clone()
+                .handle_update(
+                    NODE_ID_SETTINGS.to_owned(),
+                    PROPERTY_ID_ALARM.to_owned(),
+                    "true".to_owned()
+                )
+                .await,
+            Some("true".to_owned())
+        );
+        assert!(update_handler.device.alarm_sounding());
+    }
+
+    /// A thermometer which can't sound its alarm, like all known real devices.
+    #[derive(Clone, Debug)]
+    struct NoSoundDevice(SimulatedDevice);
+
+    impl Thermometer for NoSoundDevice {
+        async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Report> {
+            self.0.set_temperature_unit(unit).await
+        }
+
+        async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Report> {
+            self.0.set_target_range(probe, range).await
+        }
+
+        async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Report> {
+            self.0.set_target_temp(probe, target).await
+        }
+
+        async fn remove_target(&self, probe: u8) -> Result<(), Report> {
+            self.0.remove_target(probe).await
+        }
+
+        async fn enable_real_time_data(&self, enable: bool) -> Result<(), Report> {
+            self.0.enable_real_time_data(enable).await
+        }
+
+        async fn request_battery_level(&self) -> Result<(), Report> {
+            self.0.request_battery_level().await
+        }
+
+        async fn silence_alarm(&self) -> Result<(), Report> {
+            self.0.silence_alarm().await
+        }
+
+        async fn real_time(&self) -> Result<BoxStream<'static, RealTimeData>, Report> {
+            self.0.real_time().await
+        }
+
+        async fn setting_results(&self) -> Result<BoxStream<'static, SettingResult>, Report> {
+            self.0.setting_results().await
+        }
+    }
+
+    /// A simulated thermometer whose data stops until it is reconnected to.
+    #[derive(Clone, Debug)]
+    struct DroppingDevice {
+        device: SimulatedDevice,
+        connected: Arc<AtomicBool>,
+    }
+
+    impl Thermometer for DroppingDevice {
+        async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Report> {
+            self.device.set_temperature_unit(unit).await
+        }
+
+        async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Report> {
+            self.device.set_target_range(probe, range).await
+        }
+
+        async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Report> {
+            self.device.set_target_temp(probe, target).await
+        }
+
+        async fn remove_target(&self, probe: u8) -> Result<(), Report> {
+            self.device.remove_target(probe).await
+        }
+
+        async fn enable_real_time_data(&self, enable: bool) -> Result<(), Report> {
+            self.device.enable_real_time_data(enable).await
+        }
+
+        async fn request_battery_level(&self) -> Result<(), Report> {
+            self.device.request_battery_level().await
+        }
+
+        async fn silence_alarm(&self) -> Result<(), Report> {
+            self.device.silence_alarm().await
+        }
+
+        async fn real_time(&self) -> Result<BoxStream<'static, RealTimeData>, Report> {
+            if self.connected.load(Ordering::SeqCst) {
+                self.device.real_time().await
+            } else {
+                Ok(stream::empty().boxed())
+            }
+        }
+
+        async fn setting_results(&self) -> Result<BoxStream<'static, SettingResult>, Report> {
+            if self.connected.load(Ordering::SeqCst) {
+                self.device.setting_results().await
+            } else {
+                Ok(stream::empty().boxed())
+            }
+        }
+    }
+
+    /// Reconnects a `DroppingDevice`, counting how many times it does.
+    #[derive(Debug, Default)]
+    struct TestReconnector {
+        reconnects: Arc<AtomicU32>,
+    }
+
+    impl Reconnect<DroppingDevice> for TestReconnector {
+        fn reconnect<'a>(
+            &'a self,
+            device: &'a DroppingDevice,
+        ) -> BoxFuture<'a, Result<(), Report>> {
+            self.reconnects.fetch_add(1, Ordering::SeqCst);
+            device.connected.store(true, Ordering::SeqCst);
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    fn dropping_bbq(config: Config) -> Bbq<DroppingDevice> {
+        let device = DroppingDevice {
+            device: SimulatedDevice::new(config.simulate.clone()),
+            connected: Arc::new(AtomicBool::new(false)),
+        };
+        Bbq::new(
+            device,
+            MacAddress::from([0x02, 0, 0, 0, 0, 1]),
+            "Dropping BBQ".to_owned(),
+            None,
+            DeviceConfig::default(),
+            config,
+        )
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_data_stops() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mqtt.host = "127.0.0.1".to_owned();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.bbq.restore_display_unit = false;
+        config.bbq.connect_retry_delay_seconds = 0;
+        let broker = tokio::spawn(fake_broker(listener, &[]));
+        let (_bridge_commands_tx, bridge_commands) = broadcast::channel(1);
+        let reconnector = TestReconnector::default();
+        let reconnects = reconnector.reconnects.clone();
+        let mut bbq = dropping_bbq(config);
+        bbq.reconnector = Some(Box::new(reconnector));
+
+        bbq.run(None, bridge_commands, None, None, None, true)
+            .await
+            .unwrap();
+
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+        let topics = broker.await.unwrap();
+        assert!(topics
+            .iter()
+            .any(|topic| topic == "homie/cloudbbq-020000000001/probe0/temperature"));
+    }
+
+    #[tokio::test]
+    async fn run_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mqtt.host = "127.0.0.1".to_owned();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.bbq.restore_display_unit = false;
+        let broker = tokio::spawn(fake_broker(listener, &[]));
+        let (bridge_commands_tx, bridge_commands) = broadcast::channel(1);
+        bridge_commands_tx.send(BridgeCommand::Shutdown).unwrap();
+
+        Bbq::simulated(1, config)
+            .run(None, bridge_commands, None, None, None, false)
+            .await
+            .unwrap();
+
+        let topics = broker.await.unwrap();
+        assert!(topics
+            .iter()
+            .any(|topic| topic == "homie/cloudbbq-020000000001/info/bluetooth_status"));
+    }
+
+    #[tokio::test]
+    async fn data_stops_without_reconnector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mqtt.host = "127.0.0.1".to_owned();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.bbq.restore_display_unit = false;
+        let broker = tokio::spawn(fake_broker(listener, &[]));
+        let (_bridge_commands_tx, bridge_commands) = broadcast::channel(1);
+
+        assert!(dropping_bbq(config)
+            .run(None, bridge_commands, None, None, None, true)
+            .await
+            .is_err());
+        broker.abort();
+    }
+
+    #[tokio::test]
+    async fn sound_alarm_unsupported() {
+        let update_handler =
+            update_handler_for(NoSoundDevice(SimulatedDevice::new(Default::default())));
+
+        // The alarm isn't sounding, so that is what gets published.
+        assert_eq!(
+            update_handler
+                .clone()
+                .handle_update(
+                    NODE_ID_SETTINGS.to_owned(),
+                    PROPERTY_ID_ALARM.to_owned(),
+                    "true".to_owned()
+                )
+                .await,
+            Some("false".to_owned())
+        );
+        assert!(!update_handler.device.0.alarm_sounding());
+    }
+
+    #[tokio::test]
+    async fn target_preset_request() {
+        let (target_updates, mut target_updates_rx) = unbounded();
+        let update_handler = UpdateHandler {
+            target_updates,
+            target_presets: HashMap::from([(
+                "smoking".to_owned(),
+                TargetPreset {
+                    mode: PresetMode::Range,
+                    min: 105.0,
+                    max: 120.0,
+                },
+            )]),
+            ..test_update_handler()
+        };
+        let expected_target = Target {
+            mode: TargetMode::Range,
+            temperature_min: 105.0,
+            temperature_max: 120.0,
+        };
+
+        assert_eq!(
+            update_handler
+                .clone()
+                .handle_update(
+                    "probe1".to_owned(),
+                    PROPERTY_ID_TARGET_PRESET.to_owned(),
+                    "smoking".to_owned()
+                )
+                .await,
+            Some("smoking".to_owned())
+        );
+        assert_eq!(
+            update_handler
+                .clone()
+                .handle_update(
+                    "probe1".to_owned(),
+                    PROPERTY_ID_TARGET_PRESET.to_owned(),
+                    "unknown".to_owned()
+                )
+                .await,
+            None
+        );
+
+        assert_eq!(
+            target_updates_rx.try_next().unwrap(),
+            Some((1, expected_target.clone()))
+        );
+        assert!(target_updates_rx.try_next().is_err());
+        let target = update_handler.target_state.lock().await.target(1);
+        assert_eq!(*target.lock().await, expected_target);
+    }
+
+    #[test]
+    fn published_values() {
+        let mut published_values = PublishedValues::default();
+        published_values.record("battery", "percentage", "50");
+        published_values.record("probe0", "temperature", "20.5");
+        published_values.record("probe0", "temperature", "21");
+        published_values.record("probe1", "temperature", "30");
+        published_values.remove_node("probe1");
+
+        let mut values = published_values.all();
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                (
+                    ("battery".to_owned(), "percentage".to_owned()),
+                    "50".to_owned()
+                ),
+                (
+                    ("probe0".to_owned(), "temperature".to_owned()),
+                    "21".to_owned()
+                ),
+            ]
+        );
+    }
+
+    /// Interleaved updates to the minimum and maximum of the same probe should both end up applied
+    /// to the device, with the last one applied reflecting both changes.
+    #[tokio::test]
+    async fn interleaved_target_updates() {
+        let target = AsyncMutex::new(Target {
+            mode: TargetMode::Range,
+            temperature_min: 10.0,
+            temperature_max: 20.0,
+        });
+        let celsius = TemperatureUnit::Celcius;
+        let applied = Mutex::new(vec![]);
+        let apply = |target: Target| {
+            let applied = &applied;
+            async move {
+                // Give the other update a chance to run in the middle of this one.
+                yield_now().await;
+                applied.lock().unwrap().push(target);
+                Ok(())
+            }
+        };
+
+        let (min, max) = futures::join!(
+            update_target(
+                &target,
+                PROPERTY_ID_TARGET_TEMPERATURE_MIN,
+                "15",
+                celsius,
+                apply
+            ),
+            update_target(
+                &target,
+                PROPERTY_ID_TARGET_TEMPERATURE_MAX,
+                "60",
+                celsius,
+                apply
+            ),
+        );
+
+        let expected = Target {
+            mode: TargetMode::Range,
+            temperature_min: 15.0,
+            temperature_max: 60.0,
+        };
+        assert!(min.is_some());
+        assert_eq!(max, Some(expected.clone()));
+        assert_eq!(applied.lock().unwrap().last(), Some(&expected));
+        assert_eq!(*target.lock().await, expected);
     }
 }
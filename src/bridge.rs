@@ -0,0 +1,141 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A Homie device for the bridge itself, with properties which apply to every thermometer at once.
+
+use crate::bbq::{display_unit, parse_display_unit, DISPLAY_UNITS};
+use crate::config::{get_mqtt_options, Config};
+use crate::metrics::Metrics;
+use crate::notify::Notification;
+use crate::push::Reading;
+use cloudbbq::TemperatureUnit;
+use eyre::{Report, WrapErr};
+use futures::{select, FutureExt};
+use homie_device::{HomieDevice, Node, Property};
+use rustls::ClientConfig;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use tokio::sync::mpsc;
+
+const DEVICE_ID_SUFFIX: &str = "bridge";
+const NODE_ID_SETTINGS: &str = "settings";
+const PROPERTY_ID_DISPLAY_UNIT: &str = "unit";
+/// How long to wait for a Homie device's disconnection to be sent when shutting down.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command from the bridge to every running thermometer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BridgeCommand {
+    /// Set the display unit of the thermometer.
+    SetUnit(TemperatureUnit),
+    /// Stop running the thermometer, as the bridge is shutting down.
+    Shutdown,
+}
+
+/// Channels connecting each thermometer to the rest of the bridge.
+#[derive(Clone, Debug)]
+pub struct BridgeChannels {
+    /// Commands for every thermometer.
+    pub commands: Sender<BridgeCommand>,
+    /// Where to send readings to push to the REST endpoint, if it is enabled.
+    pub readings: Option<mpsc::Sender<Reading>>,
+    /// Where to send notifications for the webhook, if it is enabled.
+    pub notifications: Option<mpsc::Sender<Notification>>,
+    /// Where to record readings for the Prometheus endpoint, if it is enabled.
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+/// Send `BridgeCommand::Shutdown` to every thermometer when the process is interrupted or
+/// terminated.
+pub async fn forward_shutdown(commands: Sender<BridgeCommand>) -> Result<(), Report> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        result = signal::ctrl_c().fuse() => result?,
+        _ = terminate.recv().fuse() => {}
+    }
+    log::info!("Signal received, shutting down");
+    // It doesn't matter if there are no devices running to receive it.
+    let _ = commands.send(BridgeCommand::Shutdown);
+    Ok(())
+}
+
+/// Wait until `BridgeCommand::Shutdown` is received, ignoring any other commands.
+pub async fn shutdown_requested(commands: &mut Receiver<BridgeCommand>) {
+    loop {
+        match commands.recv().await {
+            Ok(BridgeCommand::Shutdown) | Err(RecvError::Closed) => return,
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+        }
+    }
+}
+
+/// Disconnect the given Homie device cleanly, so that its state is set to `disconnected`, and wait
+/// a little while for its event loop in `homie_handle` to send the disconnection.
+pub async fn disconnect_homie(homie: HomieDevice, homie_handle: impl Future + Unpin, name: &str) {
+    if let Err(e) = homie.disconnect().await {
+        log::warn!("Failed to disconnect {}: {}", name, e);
+        return;
+    }
+    // The event loop stops with an error once the disconnection has been sent, which doesn't
+    // matter.
+    let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, homie_handle).await;
+}
+
+/// Publish the bridge Homie device, and send commands to `commands` when its properties are set.
+/// Stops when `BridgeCommand::Shutdown` is sent.
+pub async fn run_bridge(
+    config: Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+    commands: Sender<BridgeCommand>,
+) -> Result<(), Report> {
+    let mut shutdown = commands.subscribe();
+    let name = "Barbecue thermometer bridge";
+    let device_base = config.homie.device_base(DEVICE_ID_SUFFIX, name);
+    let mqtt_options = get_mqtt_options(&config.mqtt, DEVICE_ID_SUFFIX, tls_client_config);
+    let mut homie_builder = HomieDevice::builder(&device_base, name, mqtt_options);
+    homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    homie_builder.set_update_callback(move |node_id, property_id, value| {
+        let commands = commands.clone();
+        async move {
+            if node_id != NODE_ID_SETTINGS || property_id != PROPERTY_ID_DISPLAY_UNIT {
+                return None;
+            }
+            let unit = parse_display_unit(&value)?;
+            log::info!("Setting unit of all devices to {}", value);
+            // It doesn't matter if there are no devices running to receive it.
+            let _ = commands.send(BridgeCommand::SetUnit(unit));
+            Some(display_unit(unit).to_owned())
+        }
+    });
+    let (mut homie, homie_handle) = homie_builder.spawn().await?;
+    homie
+        .add_node(Node::new(
+            NODE_ID_SETTINGS,
+            config.node_label(NODE_ID_SETTINGS, "Settings"),
+            "Settings for all devices",
+            vec![Property::enumeration(
+                PROPERTY_ID_DISPLAY_UNIT,
+                config.property_label(NODE_ID_SETTINGS, PROPERTY_ID_DISPLAY_UNIT, "Unit"),
+                true,
+                false,
+                None,
+                &DISPLAY_UNITS,
+            )],
+        ))
+        .await?;
+    homie.ready().await?;
+
+    let mut homie_handle = Box::pin(homie_handle.fuse());
+    select! {
+        homie_result = homie_handle => homie_result.wrap_err("Homie error"),
+        () = shutdown_requested(&mut shutdown).fuse() => {
+            disconnect_homie(homie, homie_handle, "bridge").await;
+            Ok(())
+        }
+    }
+}
@@ -2,46 +2,448 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
+use crate::units::{difference_to_celsius, parse_temperature_unit, to_celsius};
 use bluez_async::MacAddress;
-use eyre::Report;
-use rumqttc::{MqttOptions, TlsConfiguration, Transport};
-use rustls::{ClientConfig, RootCertStore};
+use cloudbbq::TemperatureUnit;
+use csv::{Position, Trim};
+use eyre::{bail, eyre, Report};
+use rumqttc::{MqttOptions, QoS, TlsConfiguration, Transport};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
 use serde::de::Error as _;
 use serde::{Deserialize as _, Deserializer};
 use serde_derive::Deserialize;
 use stable_eyre::eyre::WrapErr;
 use std::collections::HashMap;
-use std::fs::read_to_string;
+use std::env;
+use std::fs::{read_to_string, File};
+use std::io::{BufReader, Read};
+use std::net::SocketAddr;
+use std::str;
 use std::sync::Arc;
 use std::time::Duration;
+use url::Url;
 
 const DEFAULT_MQTT_PREFIX: &str = "homie";
+const DEFAULT_HOMEASSISTANT_PREFIX: &str = "homeassistant";
 const DEFAULT_MQTT_CLIENT_PREFIX: &str = "cloudbbq";
 const DEFAULT_DEVICE_ID_PREFIX: &str = "cloudbbq";
+const DEFAULT_DEVICE_BASE_TEMPLATE: &str = "{prefix}/{id_prefix}-{mac}";
 const DEFAULT_HOST: &str = "test.mosquitto.org";
 const DEFAULT_PORT: u16 = 1883;
-const CONFIG_FILENAME: &str = "cloudbbq-homie.toml";
-const KEEP_ALIVE: Duration = Duration::from_secs(5);
+pub const DEFAULT_CONFIG_FILENAME: &str = "cloudbbq-homie.toml";
+const ENV_MQTT_HOST: &str = "CLOUDBBQ_MQTT_HOST";
+const ENV_MQTT_USERNAME: &str = "CLOUDBBQ_MQTT_USERNAME";
+const ENV_MQTT_PASSWORD: &str = "CLOUDBBQ_MQTT_PASSWORD";
+const DEFAULT_SUMMARY_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_TEMPERATURE_PRECISION: usize = 1;
+const DEFAULT_INITIAL_DATA_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_PUSH_BATCH_SIZE: usize = 10;
+const DEFAULT_PUSH_QUEUE_SIZE: usize = 1000;
+const DEFAULT_REAL_TIME_ENABLE_RETRIES: u32 = 2;
+const DEFAULT_REAL_TIME_ENABLE_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_FAILURES_BEFORE_COOLDOWN: u32 = 3;
+const DEFAULT_RECONNECT_COOLDOWN_SECONDS: u64 = 60;
+const DEFAULT_CONNECT_ATTEMPTS: u32 = 3;
+const DEFAULT_CONNECT_RETRY_DELAY_SECONDS: u64 = 1;
+const DEFAULT_MAX_RECONNECT_COOLDOWN_SECONDS: u64 = 3600;
+const DEFAULT_DISCOVERY_PASSES: u32 = 1;
+const DEFAULT_BATTERY_POLL_INTERVAL_SECONDS: u64 = 300;
+const DEFAULT_LOW_BATTERY_PERCENTAGE: u32 = 15;
+const DEFAULT_KEEP_ALIVE_SECONDS: u64 = 5;
+/// The shortest keep-alive interval which rumqttc allows.
+const MIN_KEEP_ALIVE_SECONDS: u64 = 5;
+/// The longest cook which may be configured, a week, which is more than any real cook needs.
+const MAX_COOK_MINUTES: u64 = 7 * 24 * 60;
 
 #[derive(Clone, Debug, Default, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub mqtt: MqttConfig,
     pub homie: HomieConfig,
+    pub bbq: BbqConfig,
+    pub simulate: SimulateConfig,
     #[serde(deserialize_with = "de_device_map", rename = "device")]
     pub devices: HashMap<MacAddress, DeviceConfig>,
+    /// Path to a CSV file with extra probe names and offsets, to merge into `devices`.
+    pub probes_file: Option<String>,
+    /// The unit in which temperatures in the config file are given. They are converted to Celsius
+    /// when the config is read, or Celsius is assumed if this is not set.
+    #[serde(deserialize_with = "de_temperature_unit")]
+    pub config_unit: Option<TemperatureUnit>,
+    /// Display labels to use instead of the default English ones, keyed by node ID, or node ID and
+    /// property ID separated by a slash. Probe nodes all use the node ID `probe`.
+    pub labels: HashMap<String, String>,
+    /// Whether to retain the values of properties, overriding the defaults, keyed in the same way
+    /// as `labels`.
+    pub retained: HashMap<String, bool>,
+    pub push: PushConfig,
+    pub notify: NotifyConfig,
+    pub metrics: MetricsConfig,
+    /// Which messages to log, in the same format as the `RUST_LOG` environment variable, which
+    /// overrides it if set.
+    pub log_filter: Option<String>,
+    /// Whether to log human-readable text or JSON lines.
+    pub log_format: LogFormat,
+}
+
+/// The format to write log messages in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with structured fields such as the device MAC address.
+    Json,
 }
 
 impl Config {
-    pub fn from_file() -> Result<Config, Report> {
-        Config::read(CONFIG_FILENAME)
+    /// Get the config for the device with the given MAC address, if there is any.
+    ///
+    /// MAC addresses are compared by value, so this matches whatever case the address was written
+    /// in, in the config file or by BlueZ. A device which uses a random address won't match config
+    /// for its public address though, so the address to configure is the one shown by
+    /// `--list-devices`.
+    pub fn device_config(&self, mac_address: &MacAddress) -> Option<&DeviceConfig> {
+        self.devices.get(mac_address)
+    }
+
+    /// Get a copy of the config for the device with the given MAC address, or the default config if
+    /// it has none.
+    pub(crate) fn device_config_or_default(&self, mac_address: &MacAddress) -> DeviceConfig {
+        match self.device_config(mac_address) {
+            Some(device_config) => device_config.clone(),
+            None => {
+                log::debug!("No config for device {}, using defaults", mac_address);
+                DeviceConfig::default()
+            }
+        }
+    }
+
+    /// Whether the device with the given MAC address may be connected to, according to the allow
+    /// and deny lists.
+    pub fn device_allowed(&self, mac_address: &MacAddress) -> bool {
+        let bbq = &self.bbq;
+        bbq.allowed_macs
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(mac_address))
+            && !bbq
+                .denied_macs
+                .as_ref()
+                .is_some_and(|denied| denied.contains(mac_address))
+    }
+
+    /// Get the priority configured for the device with the given MAC address, or the default if it
+    /// has no config.
+    pub fn device_priority(&self, mac_address: &MacAddress) -> i32 {
+        self.device_config(mac_address)
+            .map_or(0, |device_config| device_config.priority)
+    }
+
+    /// Get the display label for the given node, or the default if it hasn't been overridden.
+    pub fn node_label<'a>(&'a self, node_id: &str, default: &'a str) -> &'a str {
+        self.labels.get(node_id).map_or(default, String::as_str)
+    }
+
+    /// Get the display label for the given property, or the default if it hasn't been overridden.
+    pub fn property_label<'a>(
+        &'a self,
+        node_id: &str,
+        property_id: &str,
+        default: &'a str,
+    ) -> &'a str {
+        self.labels
+            .get(&format!("{}/{}", node_id, property_id))
+            .map_or(default, String::as_str)
+    }
+
+    /// Get whether the given property should be retained, or the default if it hasn't been
+    /// overridden.
+    pub fn property_retained(&self, node_id: &str, property_id: &str, default: bool) -> bool {
+        self.retained
+            .get(&format!("{}/{}", node_id, property_id))
+            .copied()
+            .unwrap_or(default)
+    }
+
+    /// Read the config from the given file, and any probes file which it refers to.
+    ///
+    /// The MQTT host and credentials can be overridden by environment variables, which take
+    /// precedence over the values in the file. See `apply_env_overrides`.
+    pub fn read(filename: &str) -> Result<Config, Report> {
+        let config_file = read_to_string(filename)
+            .wrap_err_with(|| format!("Failed to read config file {}", filename))?;
+        let mut config: Config = toml::from_str(&config_file)?;
+        config.apply_env_overrides(|name| env::var(name).ok());
+        config.read_password_files()?;
+        if let Some(probes_file) = config.probes_file.clone() {
+            let file =
+                File::open(&probes_file).wrap_err_with(|| format!("Reading {}", probes_file))?;
+            config
+                .merge_probes_csv(file)
+                .wrap_err_with(|| format!("Parsing {}", probes_file))?;
+        }
+        config.convert_config_unit();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Start building a config in code rather than reading it from a file, starting from the same
+    /// defaults as an empty config file.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Override the global `[mqtt]` config with the environment variables `CLOUDBBQ_MQTT_HOST`,
+    /// `CLOUDBBQ_MQTT_USERNAME` and `CLOUDBBQ_MQTT_PASSWORD`, as looked up by `var`, so that
+    /// secrets needn't be kept in the config file. A variable which is set always replaces the
+    /// value from the file, even if it is empty, and the password variable also replaces any
+    /// `password_file`. Device-specific MQTT configs are not affected.
+    fn apply_env_overrides(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(host) = var(ENV_MQTT_HOST) {
+            self.mqtt.host = host;
+        }
+        if let Some(username) = var(ENV_MQTT_USERNAME) {
+            self.mqtt.username = Some(username);
+        }
+        if let Some(password) = var(ENV_MQTT_PASSWORD) {
+            self.mqtt.password = Some(password);
+            self.mqtt.password_file = None;
+        }
+    }
+
+    /// Read the password files of the global and device-specific MQTT configs, if any.
+    fn read_password_files(&mut self) -> Result<(), Report> {
+        self.mqtt
+            .read_password_file()
+            .wrap_err("Invalid [mqtt] config")?;
+        for (mac_address, device_config) in &mut self.devices {
+            if let Some(mqtt_config) = &mut device_config.mqtt {
+                mqtt_config
+                    .read_password_file()
+                    .wrap_err_with(|| format!("Invalid MQTT config for device {}", mac_address))?;
+            }
+        }
+        Ok(())
     }
 
-    fn read(filename: &str) -> Result<Config, Report> {
-        let config_file =
-            read_to_string(filename).wrap_err_with(|| format!("Reading {}", filename))?;
-        Ok(toml::from_str(&config_file)?)
+    /// Check for any invalid values which couldn't be caught when deserializing.
+    fn validate(&self) -> Result<(), Report> {
+        self.mqtt.validate().wrap_err("Invalid [mqtt] config")?;
+        self.homie.validate().wrap_err("Invalid [homie] config")?;
+        for (mac_address, device_config) in &self.devices {
+            if let Some(mqtt_config) = &device_config.mqtt {
+                mqtt_config
+                    .validate()
+                    .wrap_err_with(|| format!("Invalid MQTT config for device {}", mac_address))?;
+            }
+            for pair in device_config.battery_curve.windows(2) {
+                if pair[0].voltage >= pair[1].voltage {
+                    bail!(
+                        "battery_curve voltages must increase, but {} is followed by {} for device {}",
+                        pair[0].voltage,
+                        pair[1].voltage,
+                        mac_address
+                    );
+                }
+            }
+            if let Some(point) = device_config
+                .battery_curve
+                .iter()
+                .find(|point| point.percentage > 100)
+            {
+                bail!(
+                    "battery_curve percentage {} is over 100 for device {}",
+                    point.percentage,
+                    mac_address
+                );
+            }
+            for (probe_index, probe_alarm) in device_config.probe_alarms.iter().enumerate() {
+                if let (Some(low), Some(high)) = (probe_alarm.alarm_low, probe_alarm.alarm_high) {
+                    if low >= high {
+                        bail!(
+                            "alarm_low {} is not below alarm_high {} for probe {} of device {}",
+                            low,
+                            high,
+                            probe_index,
+                            mac_address
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(max_cook_minutes) = self.bbq.max_cook_minutes {
+            if max_cook_minutes > MAX_COOK_MINUTES {
+                bail!(
+                    "max_cook_minutes {} is over the maximum of {}",
+                    max_cook_minutes,
+                    MAX_COOK_MINUTES
+                );
+            }
+        }
+        for (name, preset) in &self.bbq.target_presets {
+            if preset.mode != PresetMode::Single && preset.min >= preset.max {
+                bail!(
+                    "min {} is not below max {} for target preset {}",
+                    preset.min,
+                    preset.max,
+                    name
+                );
+            }
+        }
+        Ok(())
     }
+
+    /// Convert all temperatures in the config from `config_unit` to Celsius, and then clear
+    /// `config_unit` so they won't be converted again.
+    fn convert_config_unit(&mut self) {
+        let unit = match self.config_unit.take() {
+            Some(unit) => unit,
+            None => return,
+        };
+        for device_config in self.devices.values_mut() {
+            for offset in &mut device_config.probe_offsets {
+                *offset = difference_to_celsius(*offset, unit);
+            }
+            for probe_alarm in &mut device_config.probe_alarms {
+                probe_alarm.alarm_low = probe_alarm.alarm_low.map(|low| to_celsius(low, unit));
+                probe_alarm.alarm_high = probe_alarm.alarm_high.map(|high| to_celsius(high, unit));
+            }
+        }
+        self.bbq.publish_delta = difference_to_celsius(self.bbq.publish_delta, unit);
+        for preset in self.bbq.target_presets.values_mut() {
+            preset.min = to_celsius(preset.min, unit);
+            preset.max = to_celsius(preset.max, unit);
+        }
+        self.simulate.ambient_temperature = to_celsius(self.simulate.ambient_temperature, unit);
+        self.simulate.max_temperature = to_celsius(self.simulate.max_temperature, unit);
+    }
+
+    /// Merge probe names and offsets from CSV with columns `mac`, `probe_index`, `name` and
+    /// `offset` into the device configs. The `name` and `offset` columns may be left empty.
+    fn merge_probes_csv(&mut self, reader: impl Read) -> Result<(), Report> {
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader);
+        let headers = csv_reader.headers()?.clone();
+        for result in csv_reader.records() {
+            let record = result?;
+            let line = record.position().map_or(0, Position::line);
+            let row: ProbeRow = record
+                .deserialize(Some(&headers))
+                .wrap_err_with(|| format!("Invalid row on line {}", line))?;
+            let mac_address: MacAddress = row
+                .mac
+                .parse()
+                .wrap_err_with(|| format!("Invalid MAC address on line {}", line))?;
+            let device_config = self.devices.entry(mac_address).or_default();
+            let probe_index = row.probe_index as usize;
+            if let Some(name) = row.name {
+                if device_config.probe_names.len() <= probe_index {
+                    let start = device_config.probe_names.len();
+                    device_config
+                        .probe_names
+                        .extend((start..=probe_index).map(|i| format!("Probe {}", i + 1)));
+                }
+                device_config.probe_names[probe_index] = name;
+            }
+            if let Some(offset) = row.offset {
+                if device_config.probe_offsets.len() <= probe_index {
+                    device_config.probe_offsets.resize(probe_index + 1, 0.0);
+                }
+                device_config.probe_offsets[probe_index] = offset;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`Config`] programmatically, such as when embedding the bridge in another program.
+///
+/// Unlike [`Config::read`], this doesn't look at environment variables or the probes file, but MQTT
+/// password files are read when the config is built, as for a config file.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Set the hostname of the MQTT broker.
+    pub fn mqtt_host(mut self, host: impl Into<String>) -> Self {
+        self.config.mqtt.host = host.into();
+        self
+    }
+
+    /// Set the port of the MQTT broker.
+    pub fn mqtt_port(mut self, port: u16) -> Self {
+        self.config.mqtt.port = port;
+        self
+    }
+
+    /// Set whether to connect to the MQTT broker over TLS.
+    pub fn mqtt_tls(mut self, use_tls: bool) -> Self {
+        self.config.mqtt.use_tls = use_tls;
+        self
+    }
+
+    /// Set a PEM file with extra CA certificates to trust for the MQTT broker.
+    pub fn mqtt_ca_cert_path(mut self, path: impl Into<String>) -> Self {
+        self.config.mqtt.ca_cert_path = Some(path.into());
+        self
+    }
+
+    /// Set the username and password to authenticate to the MQTT broker with.
+    pub fn mqtt_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.config.mqtt.username = Some(username.into());
+        self.config.mqtt.password = Some(password.into());
+        self
+    }
+
+    /// Set the base topic under which Homie devices are published.
+    pub fn homie_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.homie.prefix = prefix.into();
+        self
+    }
+
+    /// Set the prefix of the ID of each Homie device, which is followed by its MAC address.
+    pub fn device_id_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.homie.device_id_prefix = prefix.into();
+        self
+    }
+
+    /// Set the options for how every thermometer is handled.
+    pub fn bbq(mut self, bbq: BbqConfig) -> Self {
+        self.config.bbq = bbq;
+        self
+    }
+
+    /// Set the config for the device with the given MAC address, replacing any which was set
+    /// before.
+    pub fn device(mut self, mac_address: MacAddress, device_config: DeviceConfig) -> Self {
+        self.config.devices.insert(mac_address, device_config);
+        self
+    }
+
+    /// Read any MQTT password files and check the config in the same way as when it is read from a
+    /// file, and return it if it is valid.
+    pub fn build(mut self) -> Result<Config, Report> {
+        self.config.read_password_files()?;
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// A row of the probes CSV file.
+#[derive(Clone, Debug, Deserialize)]
+struct ProbeRow {
+    mac: String,
+    probe_index: u8,
+    name: Option<String>,
+    offset: Option<f32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -52,7 +454,61 @@ pub struct MqttConfig {
     pub use_tls: bool,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Path to a file containing the password, such as a mounted secret, instead of `password`.
+    pub password_file: Option<String>,
     pub client_prefix: String,
+    /// Path to a PEM file with the certificate chain to authenticate to the broker with, if any.
+    pub client_cert_path: Option<String>,
+    /// Path to a PEM file with the private key for `client_cert_path`.
+    pub client_key_path: Option<String>,
+    /// Path to a PEM file with extra CA certificates to trust for the broker, if any.
+    pub ca_cert_path: Option<String>,
+    /// Whether to trust the platform's CA certificates as well as those in `ca_cert_path`.
+    pub native_certs: bool,
+    /// How often to send a keep-alive ping to the broker when nothing else has been sent.
+    pub keep_alive_seconds: u64,
+    /// The QoS level to publish messages with, from 0 to 2. This only applies to messages which
+    /// the bridge publishes itself, such as Home Assistant discovery config, as homie-device always
+    /// publishes Homie properties with QoS 1 and doesn't allow changing it.
+    pub qos: u8,
+}
+
+impl MqttConfig {
+    /// Read the password from `password_file` if it is set, ignoring any trailing newline.
+    fn read_password_file(&mut self) -> Result<(), Report> {
+        if let Some(password_file) = &self.password_file {
+            if self.password.is_some() {
+                bail!("password and password_file can't both be set");
+            }
+            let password = read_to_string(password_file)
+                .wrap_err_with(|| format!("Failed to read password file {}", password_file))?;
+            self.password = Some(password.trim_end_matches(&['\r', '\n'][..]).to_owned());
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Report> {
+        if self.keep_alive_seconds < MIN_KEEP_ALIVE_SECONDS {
+            bail!(
+                "keep_alive_seconds must be at least {}, not {}",
+                MIN_KEEP_ALIVE_SECONDS,
+                self.keep_alive_seconds
+            );
+        }
+        if self.qos > 2 {
+            bail!("qos must be 0, 1 or 2, not {}", self.qos);
+        }
+        Ok(())
+    }
+
+    /// The QoS level to publish messages with.
+    pub fn qos(&self) -> QoS {
+        match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        }
+    }
 }
 
 impl Default for MqttConfig {
@@ -63,7 +519,14 @@ impl Default for MqttConfig {
             use_tls: false,
             username: None,
             password: None,
+            password_file: None,
             client_prefix: DEFAULT_MQTT_CLIENT_PREFIX.to_owned(),
+            client_cert_path: None,
+            client_key_path: None,
+            ca_cert_path: None,
+            native_certs: true,
+            keep_alive_seconds: DEFAULT_KEEP_ALIVE_SECONDS,
+            qos: 1,
         }
     }
 }
@@ -73,6 +536,14 @@ impl Default for MqttConfig {
 pub struct HomieConfig {
     pub device_id_prefix: String,
     pub prefix: String,
+    /// Whether to publish a Homie device for the bridge itself, with settings for all devices.
+    pub bridge_device: bool,
+    /// Whether to also publish Home Assistant MQTT discovery config for the sensors of each device.
+    pub homeassistant_discovery: bool,
+    /// The Home Assistant MQTT discovery prefix.
+    pub homeassistant_prefix: String,
+    /// The template for the base topic of each Homie device.
+    pub device_base_template: String,
 }
 
 impl Default for HomieConfig {
@@ -80,6 +551,376 @@ impl Default for HomieConfig {
         HomieConfig {
             device_id_prefix: DEFAULT_DEVICE_ID_PREFIX.to_owned(),
             prefix: DEFAULT_MQTT_PREFIX.to_owned(),
+            bridge_device: false,
+            homeassistant_discovery: false,
+            homeassistant_prefix: DEFAULT_HOMEASSISTANT_PREFIX.to_owned(),
+            device_base_template: DEFAULT_DEVICE_BASE_TEMPLATE.to_owned(),
+        }
+    }
+}
+
+impl HomieConfig {
+    fn validate(&self) -> Result<(), Report> {
+        if self.prefix.is_empty() {
+            bail!("prefix must not be empty");
+        }
+        if self.device_id_prefix.is_empty() {
+            bail!("device_id_prefix must not be empty");
+        }
+        // Without the MAC address different devices could end up with the same topic.
+        if !self.device_base_template.contains("{mac}") {
+            bail!(
+                "device_base_template {:?} must contain {{mac}}",
+                self.device_base_template
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the base topic for the Homie device with the given device ID suffix and name, from
+    /// `device_base_template`.
+    pub fn device_base(&self, device_id_suffix: &str, name: &str) -> String {
+        // Homie topic IDs may only contain lowercase letters, digits and hyphens.
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        self.device_base_template
+            .replace("{prefix}", &self.prefix)
+            .replace("{id_prefix}", &self.device_id_prefix)
+            .replace("{mac}", device_id_suffix)
+            .replace("{name}", &name)
+    }
+}
+
+/// Options for how every thermometer is handled.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BbqConfig {
+    /// Whether a probe temperature exactly equal to its target counts as reaching it.
+    pub target_comparison: TargetComparison,
+    /// What to publish for target properties which aren't set.
+    pub unset_target_value: UnsetTargetValue,
+    /// Whether to publish temperatures in the unit the device is displaying, rather than always in
+    /// Celsius.
+    pub publish_display_unit: bool,
+    /// Whether to also publish the temperature of each probe in the other unit to the one it is
+    /// published in, as `temperature_f` or `temperature_c`.
+    pub publish_other_unit: bool,
+    /// The unit to set devices to display when they are connected, or Celsius if this is not set.
+    #[serde(deserialize_with = "de_temperature_unit")]
+    pub default_unit: Option<TemperatureUnit>,
+    /// A template for a human-readable summary of each device to publish, if any.
+    pub summary_template: Option<String>,
+    /// The minimum time between publishing updates to the summary.
+    pub summary_interval_seconds: u64,
+    /// Whether to wait until the first real-time data and battery level have been received from a
+    /// device before marking it as ready.
+    pub wait_for_initial_data: bool,
+    /// How long to wait for the initial data before marking the device as ready anyway.
+    pub initial_data_timeout_seconds: u64,
+    /// How often to request the battery level from each device, or 0 to only request it once.
+    pub battery_poll_interval_seconds: u64,
+    /// The battery percentage below which the battery is reported as low.
+    pub low_battery_percentage: u32,
+    /// How many times to enable real-time data again if none arrives from a device after it is
+    /// first enabled.
+    pub real_time_enable_retries: u32,
+    /// How long to wait for real-time data before enabling it again.
+    pub real_time_enable_timeout_seconds: u64,
+    /// Whether to publish the rate at which real-time data packets arrive from each device.
+    pub publish_packet_rate: bool,
+    /// Whether to publish how many values have failed to publish for each device.
+    pub publish_error_count: bool,
+    /// How many times in a row a device may fail before waiting for a cooldown before
+    /// reconnecting.
+    pub failures_before_cooldown: u32,
+    /// How many times to try connecting to and authenticating with a device before giving up on
+    /// that connection. This is also how many times to try reconnecting to a device whose data
+    /// stops, before its Homie device is torn down.
+    pub connect_attempts: u32,
+    /// How long to wait before the second attempt to connect, which doubles with each further
+    /// attempt.
+    pub connect_retry_delay_seconds: u64,
+    /// The initial cooldown, which doubles with each further failure.
+    pub reconnect_cooldown_seconds: u64,
+    /// The maximum cooldown.
+    pub max_reconnect_cooldown_seconds: u64,
+    /// How many times in a row a device may fail before giving up on it, if there is a limit.
+    pub max_reconnect_attempts: Option<u32>,
+    /// How long to keep a device in the alert state after it stops, before reconnecting to it.
+    pub grace_period_seconds: u64,
+    /// Whether to only discover devices which advertise the BBQ service.
+    pub discovery_filter: bool,
+    /// The name (such as `hci1`) or MAC address of the Bluetooth adapter to use, or `None` for all.
+    pub adapter: Option<String>,
+    /// How many scans to run at startup to find devices before connecting to them.
+    pub discovery_passes: u32,
+    /// How often to scan for new devices after startup, if at all.
+    pub rediscovery_interval_seconds: Option<u64>,
+    /// How long to keep scanning at startup if no devices are found and rediscovery is disabled,
+    /// or `None` to keep scanning forever.
+    pub discovery_timeout_seconds: Option<u64>,
+    /// If this is set, only devices with these MAC addresses are connected to.
+    #[serde(deserialize_with = "de_mac_addresses")]
+    pub allowed_macs: Option<Vec<MacAddress>>,
+    /// Devices with these MAC addresses are never connected to.
+    #[serde(deserialize_with = "de_mac_addresses")]
+    pub denied_macs: Option<Vec<MacAddress>>,
+    /// How long a cook may run before warning that it has been going too long, if at all.
+    pub max_cook_minutes: Option<u64>,
+    /// Whether to also sound the device alarm when the maximum cook duration is exceeded.
+    pub cook_timeout_alarm: bool,
+    /// Whether to also sound the device alarm when a probe goes outside its alarm thresholds.
+    pub probe_alarm_sound: bool,
+    /// The number of readings from each probe to average, to smooth out jitter. 1 disables
+    /// smoothing.
+    pub smoothing_window: usize,
+    /// How many readings in a row a probe must be missing from before its node is removed.
+    pub probe_removal_readings: u32,
+    /// How long a probe must be missing for before its node is removed.
+    pub probe_removal_seconds: u64,
+    /// The minimum time between publishing the temperature of a probe, unless it changes by more
+    /// than `publish_delta`.
+    pub min_publish_interval_seconds: u64,
+    /// The change in ºC of a probe's temperature which causes it to be published before
+    /// `min_publish_interval_seconds` has elapsed.
+    pub publish_delta: f32,
+    /// How often to publish the elapsed time of the cooking timer while it is running.
+    pub timer_publish_interval_seconds: u64,
+    /// Whether to set all targets on the device again after changing its display unit.
+    pub resync_targets_on_unit_change: bool,
+    /// Whether to set each device to the display unit last published for it over MQTT, if the
+    /// broker has retained one, rather than `default_unit`.
+    pub restore_display_unit: bool,
+    /// The number of decimal places to publish temperatures with.
+    pub temperature_precision: usize,
+    /// Whether to check that each device works properly after connecting to it.
+    pub self_test: bool,
+    /// A file in which to save probe targets, so that they are restored after restarting.
+    pub state_file: Option<String>,
+    /// Named targets which can be applied to a probe by setting its preset property, by name.
+    pub target_presets: HashMap<String, TargetPreset>,
+}
+
+impl Default for BbqConfig {
+    fn default() -> BbqConfig {
+        BbqConfig {
+            target_comparison: TargetComparison::default(),
+            unset_target_value: UnsetTargetValue::default(),
+            publish_display_unit: false,
+            publish_other_unit: false,
+            default_unit: None,
+            summary_template: None,
+            summary_interval_seconds: DEFAULT_SUMMARY_INTERVAL_SECONDS,
+            wait_for_initial_data: false,
+            initial_data_timeout_seconds: DEFAULT_INITIAL_DATA_TIMEOUT_SECONDS,
+            battery_poll_interval_seconds: DEFAULT_BATTERY_POLL_INTERVAL_SECONDS,
+            low_battery_percentage: DEFAULT_LOW_BATTERY_PERCENTAGE,
+            real_time_enable_retries: DEFAULT_REAL_TIME_ENABLE_RETRIES,
+            real_time_enable_timeout_seconds: DEFAULT_REAL_TIME_ENABLE_TIMEOUT_SECONDS,
+            publish_packet_rate: false,
+            publish_error_count: false,
+            failures_before_cooldown: DEFAULT_FAILURES_BEFORE_COOLDOWN,
+            connect_attempts: DEFAULT_CONNECT_ATTEMPTS,
+            connect_retry_delay_seconds: DEFAULT_CONNECT_RETRY_DELAY_SECONDS,
+            reconnect_cooldown_seconds: DEFAULT_RECONNECT_COOLDOWN_SECONDS,
+            max_reconnect_cooldown_seconds: DEFAULT_MAX_RECONNECT_COOLDOWN_SECONDS,
+            max_reconnect_attempts: None,
+            grace_period_seconds: 0,
+            discovery_filter: true,
+            adapter: None,
+            discovery_passes: DEFAULT_DISCOVERY_PASSES,
+            rediscovery_interval_seconds: None,
+            discovery_timeout_seconds: None,
+            allowed_macs: None,
+            denied_macs: None,
+            max_cook_minutes: None,
+            cook_timeout_alarm: false,
+            probe_alarm_sound: false,
+            smoothing_window: 1,
+            probe_removal_readings: 1,
+            probe_removal_seconds: 0,
+            min_publish_interval_seconds: 0,
+            publish_delta: 0.0,
+            timer_publish_interval_seconds: 10,
+            resync_targets_on_unit_change: true,
+            restore_display_unit: true,
+            temperature_precision: DEFAULT_TEMPERATURE_PRECISION,
+            self_test: false,
+            state_file: None,
+            target_presets: HashMap::new(),
+        }
+    }
+}
+
+/// A named target which can be applied to any probe.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TargetPreset {
+    #[serde(default)]
+    pub mode: PresetMode,
+    /// The minimum temperature in ºC, for range and hold modes.
+    #[serde(default)]
+    pub min: f32,
+    /// The target or maximum temperature in ºC.
+    pub max: f32,
+}
+
+/// The target mode which a preset sets.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresetMode {
+    /// Alarm once the temperature reaches the maximum.
+    #[default]
+    Single,
+    /// Alarm once the temperature goes outside the range.
+    Range,
+    /// Like `Range`, but keep alarming for as long as the temperature is outside the range.
+    Hold,
+}
+
+/// How a probe temperature is compared to its target.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetComparison {
+    /// The target is reached once the temperature is equal to it or beyond.
+    #[default]
+    Inclusive,
+    /// The target is only reached once the temperature is strictly beyond it.
+    Exclusive,
+}
+
+/// What to publish for target properties which don't apply in the current target mode, such as
+/// the setpoint of a range or any temperature when there is no target.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsetTargetValue {
+    /// The temperature which would be used if the mode were changed.
+    #[default]
+    Default,
+    /// An empty value.
+    Empty,
+}
+
+/// The unit in which a device reports real-time temperature data.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum RealTimeUnit {
+    /// Always Celsius, whatever the display unit. This is what all known devices do.
+    #[default]
+    #[serde(rename = "C")]
+    Celsius,
+    /// Always Fahrenheit.
+    #[serde(rename = "F")]
+    Fahrenheit,
+    /// Whichever unit the device is currently set to display.
+    #[serde(rename = "display")]
+    Display,
+}
+
+impl RealTimeUnit {
+    /// The unit which real-time data is in, when the device is displaying the given unit.
+    pub fn unit(self, display_unit: TemperatureUnit) -> TemperatureUnit {
+        match self {
+            Self::Celsius => TemperatureUnit::Celcius,
+            Self::Fahrenheit => TemperatureUnit::Fahrenheit,
+            Self::Display => display_unit,
+        }
+    }
+}
+
+/// Options for mirroring readings to a REST endpoint.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushConfig {
+    /// The URL to POST readings to, if any.
+    pub url: Option<Url>,
+    /// The value of the `Authorization` header to send, if any.
+    pub authorization: Option<String>,
+    /// The maximum number of readings to send in a single request.
+    pub batch_size: usize,
+    /// The maximum number of readings to queue while waiting for earlier requests to finish.
+    pub queue_size: usize,
+}
+
+impl Default for PushConfig {
+    fn default() -> PushConfig {
+        PushConfig {
+            url: None,
+            authorization: None,
+            batch_size: DEFAULT_PUSH_BATCH_SIZE,
+            queue_size: DEFAULT_PUSH_QUEUE_SIZE,
+        }
+    }
+}
+
+/// Options for calling a webhook when a device alarms.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// The URL to POST notifications to, if any.
+    pub url: Option<Url>,
+    /// The value of the `Authorization` header to send, if any.
+    pub authorization: Option<String>,
+    /// What to send in the body of each request.
+    pub format: NotifyFormat,
+}
+
+/// Options for serving metrics for Prometheus to scrape.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The address to listen for HTTP requests on, if the metrics endpoint is enabled.
+    pub listen_address: Option<SocketAddr>,
+}
+
+/// The format of the body of a notification request.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyFormat {
+    /// A JSON object with the device, probe, reason and temperature.
+    #[default]
+    Json,
+    /// A human-readable message, as expected by services such as ntfy.
+    Text,
+}
+
+/// Options for the fake thermometers used with `--simulate`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulateConfig {
+    /// The number of simulated thermometers.
+    pub devices: u8,
+    /// The number of probes on each simulated thermometer.
+    pub probes: u8,
+    /// How often each simulated thermometer sends real-time data.
+    pub interval_seconds: u64,
+    /// The temperature at which the probes start.
+    pub ambient_temperature: f32,
+    /// The temperature which the hottest probe heats up to.
+    pub max_temperature: f32,
+    /// The time constant for the probes heating up, in minutes.
+    pub heating_minutes: f32,
+    /// How long the simulated battery takes to drain completely, in hours.
+    pub battery_hours: f32,
+}
+
+impl Default for SimulateConfig {
+    fn default() -> SimulateConfig {
+        SimulateConfig {
+            devices: 1,
+            probes: 4,
+            interval_seconds: 1,
+            ambient_temperature: 20.0,
+            max_temperature: 90.0,
+            heating_minutes: 30.0,
+            battery_hours: 24.0,
         }
     }
 }
@@ -89,6 +930,58 @@ impl Default for HomieConfig {
 pub struct DeviceConfig {
     pub name: Option<String>,
     pub probe_names: Vec<String>,
+    /// Calibration offsets to add to the temperature of each probe, by index.
+    pub probe_offsets: Vec<f32>,
+    /// The number labelled on the device for the port of each probe, by index.
+    pub probe_ports: Vec<u32>,
+    /// Thresholds to alarm at for each probe, by index, independent of the targets set on the
+    /// device.
+    pub probe_alarms: Vec<ProbeAlarmConfig>,
+    /// The battery voltage to treat as 0%, rather than 0.
+    pub battery_voltage_min: Option<u16>,
+    /// The battery voltage to treat as 100%, rather than the maximum reported by the device.
+    pub battery_voltage_max: Option<u16>,
+    /// Points mapping battery voltage to percentage, in increasing order of voltage, to interpolate
+    /// between for batteries whose voltage isn't linear with charge. If this is empty then
+    /// `battery_voltage_min` and `battery_voltage_max` are used instead.
+    pub battery_curve: Vec<BatteryCurvePoint>,
+    /// A custom credential to send to authenticate with the device, as a hex string, for
+    /// thermometers which don't accept the standard one.
+    #[serde(deserialize_with = "de_hex")]
+    pub auth_credential: Option<Vec<u8>>,
+    /// MQTT broker settings to use for this device instead of the global ones. Any fields which
+    /// are not set take their default values, not those from the global settings.
+    pub mqtt: Option<MqttConfig>,
+    /// Connecting to devices with a higher priority is started first. Devices with the same
+    /// priority are started in the order they were discovered.
+    pub priority: i32,
+    /// Indices of probes whose temperatures to average and publish as a separate node, if any.
+    pub average_probes: Vec<u8>,
+    /// The unit in which the device reports real-time temperatures.
+    pub realtime_unit: RealTimeUnit,
+    /// The unit to set the device to display when it is connected, instead of `bbq.default_unit`.
+    #[serde(deserialize_with = "de_temperature_unit")]
+    pub unit: Option<TemperatureUnit>,
+}
+
+/// A point on a battery discharge curve.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryCurvePoint {
+    /// The battery voltage in millivolts.
+    pub voltage: u16,
+    /// The charge percentage at that voltage.
+    pub percentage: u32,
+}
+
+/// Temperatures in ºC outside which a probe should alarm.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeAlarmConfig {
+    /// Alarm when the probe temperature drops below this.
+    pub alarm_low: Option<f32>,
+    /// Alarm when the probe temperature rises above this.
+    pub alarm_high: Option<f32>,
 }
 
 pub fn de_device_map<'de, D: Deserializer<'de>>(
@@ -105,27 +998,111 @@ pub fn de_device_map<'de, D: Deserializer<'de>>(
         .collect()
 }
 
+pub fn de_mac_addresses<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Vec<MacAddress>>, D::Error> {
+    let mac_addresses: Option<Vec<String>> = Option::deserialize(d)?;
+    mac_addresses
+        .map(|mac_addresses| {
+            mac_addresses
+                .iter()
+                .map(|mac_address| mac_address.parse().map_err(D::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+pub fn de_temperature_unit<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<TemperatureUnit>, D::Error> {
+    let unit = String::deserialize(d)?;
+    parse_temperature_unit(&unit)
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("Invalid temperature unit {:?}", unit)))
+}
+
+pub fn de_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+    let hex = String::deserialize(d)?;
+    parse_hex(&hex).map(Some).map_err(D::Error::custom)
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|byte| {
+            str::from_utf8(byte)
+                .ok()
+                .filter(|byte| byte.len() == 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("Invalid hex string {:?}", hex))
+        })
+        .collect()
+}
+
 /// Construct a `ClientConfig` for TLS connections to the MQTT broker, if TLS is enabled.
-pub fn get_tls_client_config(config: &MqttConfig) -> Option<Arc<ClientConfig>> {
+pub fn get_tls_client_config(config: &MqttConfig) -> Result<Option<Arc<ClientConfig>>, Report> {
     if config.use_tls {
         let mut root_store = RootCertStore::empty();
-        for cert in
-            rustls_native_certs::load_native_certs().expect("Failed to load platform certificates.")
-        {
-            root_store.add(&rustls::Certificate(cert.0)).unwrap();
+        if config.native_certs {
+            for cert in rustls_native_certs::load_native_certs()
+                .wrap_err("Failed to load platform certificates")?
+            {
+                root_store.add(&Certificate(cert.0))?;
+            }
+        }
+        if let Some(ca_cert_path) = &config.ca_cert_path {
+            for cert in read_certificates(ca_cert_path)? {
+                root_store
+                    .add(&cert)
+                    .wrap_err_with(|| format!("Invalid CA certificate in {}", ca_cert_path))?;
+            }
         }
-        let client_config = ClientConfig::builder()
+        let builder = ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        Some(Arc::new(client_config))
+            .with_root_certificates(root_store);
+        let client_config = match (&config.client_cert_path, &config.client_key_path) {
+            (Some(cert_path), Some(key_path)) => builder
+                .with_client_auth_cert(read_certificates(cert_path)?, read_private_key(key_path)?)
+                .wrap_err("Invalid client certificate or key")?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => bail!("client_cert_path and client_key_path must be set together"),
+        };
+        Ok(Some(Arc::new(client_config)))
     } else {
-        None
+        Ok(None)
     }
 }
 
+/// Read all certificates from the given PEM file.
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, Report> {
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .wrap_err_with(|| format!("Failed to parse {}", path))?;
+    if certificates.is_empty() {
+        bail!("No certificates found in {}", path);
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Read the first private key from the given PEM file.
+fn read_private_key(path: &str) -> Result<PrivateKey, Report> {
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .wrap_err_with(|| format!("Failed to parse {}", path))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("No private key found in {}", path))
+}
+
 /// Construct the `MqttOptions` for connecting to the MQTT broker based on configuration options or
 /// defaults.
+///
+/// This doesn't set a Last Will, as `HomieDevice` adds one itself when it is spawned to set the
+/// device's `$state` to `lost` if the connection drops uncleanly.
 pub fn get_mqtt_options(
     config: &MqttConfig,
     client_name_suffix: &str,
@@ -133,7 +1110,7 @@ pub fn get_mqtt_options(
 ) -> MqttOptions {
     let client_name = format!("{}-{}", config.client_prefix, client_name_suffix);
     let mut mqtt_options = MqttOptions::new(client_name, &config.host, config.port);
-    mqtt_options.set_keep_alive(KEEP_ALIVE);
+    mqtt_options.set_keep_alive(Duration::from_secs(config.keep_alive_seconds));
 
     if let (Some(username), Some(password)) = (&config.username, &config.password) {
         mqtt_options.set_credentials(username, password);
@@ -151,6 +1128,217 @@ pub fn get_mqtt_options(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::env::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn tls_client_cert_not_pem() {
+        let config = MqttConfig {
+            use_tls: true,
+            client_cert_path: Some("Cargo.toml".to_owned()),
+            client_key_path: Some("Cargo.toml".to_owned()),
+            ..Default::default()
+        };
+        let error = get_tls_client_config(&config).unwrap_err();
+        assert_eq!(error.to_string(), "No certificates found in Cargo.toml");
+        assert_eq!(
+            read_private_key("Cargo.toml").unwrap_err().to_string(),
+            "No private key found in Cargo.toml"
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            host = "file.example.com"
+            username = "file-user"
+            password = "file-password"
+            "#,
+        )
+        .unwrap();
+        config.apply_env_overrides(|name| match name {
+            "CLOUDBBQ_MQTT_HOST" => Some("env.example.com".to_owned()),
+            "CLOUDBBQ_MQTT_PASSWORD" => Some("env-password".to_owned()),
+            _ => None,
+        });
+        assert_eq!(config.mqtt.host, "env.example.com");
+        assert_eq!(config.mqtt.username.as_deref(), Some("file-user"));
+        assert_eq!(config.mqtt.password.as_deref(), Some("env-password"));
+    }
+
+    #[test]
+    fn password_file() {
+        let path = temp_dir().join("cloudbbq-homie-test-password");
+        fs::write(&path, "secret\n").unwrap();
+        let mut config = MqttConfig {
+            password_file: Some(path.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        config.read_password_file().unwrap();
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(
+            config.read_password_file().unwrap_err().to_string(),
+            "password and password_file can't both be set"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq]
+            allowed_macs = ["00:11:22:AA:BB:CC", "00:11:22:aa:bb:dd"]
+            denied_macs = ["00:11:22:aa:bb:dd"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.device_allowed(&"00:11:22:aa:bb:cc".parse().unwrap()));
+        assert!(!config.device_allowed(&"00:11:22:aa:bb:dd".parse().unwrap()));
+        assert!(!config.device_allowed(&"00:11:22:aa:bb:ee".parse().unwrap()));
+
+        let config = Config::default();
+        assert!(config.device_allowed(&"00:11:22:aa:bb:ee".parse().unwrap()));
+
+        assert!(toml::from_str::<Config>(
+            r#"
+            [bbq]
+            denied_macs = ["invalid"]
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn default_unit() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq]
+            default_unit = "ºF"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bbq.default_unit, Some(TemperatureUnit::Fahrenheit));
+        assert_eq!(Config::default().bbq.default_unit, None);
+    }
+
+    #[test]
+    fn device_unit() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq]
+            default_unit = "C"
+            [device."00:11:22:aa:bb:cc"]
+            unit = "F"
+            [device."00:11:22:aa:bb:dd"]
+            name = "Kitchen"
+            "#,
+        )
+        .unwrap();
+        let device_unit = |mac: &str| config.devices[&mac.parse().unwrap()].unit;
+        assert_eq!(
+            device_unit("00:11:22:aa:bb:cc"),
+            Some(TemperatureUnit::Fahrenheit)
+        );
+        assert_eq!(device_unit("00:11:22:aa:bb:dd"), None);
+    }
+
+    #[test]
+    fn missing_config_file() {
+        assert_eq!(
+            Config::read("missing.toml").unwrap_err().to_string(),
+            "Failed to read config file missing.toml"
+        );
+    }
+
+    #[test]
+    fn max_cook_minutes_too_long() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq]
+            max_cook_minutes = 100000
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq]
+            max_cook_minutes = 720
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn keep_alive_too_short() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            keep_alive_seconds = 0
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        let config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            keep_alive_seconds = 30
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn qos() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            qos = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Invalid [mqtt] config"
+        );
+        let config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            qos = 0
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.mqtt.qos(), QoS::AtMostOnce);
+        assert_eq!(MqttConfig::default().qos(), QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn tls_ca_cert_missing() {
+        let config = MqttConfig {
+            use_tls: true,
+            ca_cert_path: Some("missing.pem".to_owned()),
+            native_certs: false,
+            ..Default::default()
+        };
+        let error = get_tls_client_config(&config).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to open missing.pem");
+    }
+
+    #[test]
+    fn tls_client_cert_without_key() {
+        let config = MqttConfig {
+            use_tls: true,
+            client_cert_path: Some("cert.pem".to_owned()),
+            ..Default::default()
+        };
+        assert!(get_tls_client_config(&config).is_err());
+    }
 
     /// Parsing the example config file should not give any errors.
     #[test]
@@ -158,6 +1346,388 @@ mod tests {
         Config::read("cloudbbq-homie.example.toml").unwrap();
     }
 
+    #[test]
+    fn device_config_case_insensitive() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:AA:BB:CC"]
+            name = "Upper"
+            [device."00:11:22:aa:bb:dd"]
+            name = "Lower"
+            "#,
+        )
+        .unwrap();
+
+        let upper = config.device_config(&"00:11:22:aa:bb:cc".parse().unwrap());
+        assert_eq!(upper.unwrap().name.as_deref(), Some("Upper"));
+        let lower = config.device_config(&"00:11:22:AA:BB:DD".parse().unwrap());
+        assert_eq!(lower.unwrap().name.as_deref(), Some("Lower"));
+        assert!(config
+            .device_config(&"00:11:22:aa:bb:ee".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn device_config_or_default() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:AA:BB:CC"]
+            name = "Grill"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config
+                .device_config_or_default(&"00:11:22:aa:bb:cc".parse().unwrap())
+                .name
+                .as_deref(),
+            Some("Grill")
+        );
+        assert!(config
+            .device_config_or_default(&"00:11:22:aa:bb:dd".parse().unwrap())
+            .name
+            .is_none());
+    }
+
+    #[test]
+    fn probes_csv() {
+        let mut config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            probe_names = ["First probe"]
+            "#,
+        )
+        .unwrap();
+        config
+            .merge_probes_csv(
+                "mac,probe_index,name,offset\n\
+                 00:11:22:aa:bb:cc,2,Third probe,-1.5\n\
+                 00:11:22:aa:bb:dd,0,,3\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let device = &config.devices[&"00:11:22:aa:bb:cc".parse().unwrap()];
+        assert_eq!(
+            device.probe_names,
+            vec!["First probe", "Probe 2", "Third probe"]
+        );
+        assert_eq!(device.probe_offsets, vec![0.0, 0.0, -1.5]);
+        let device = &config.devices[&"00:11:22:aa:bb:dd".parse().unwrap()];
+        assert!(device.probe_names.is_empty());
+        assert_eq!(device.probe_offsets, vec![3.0]);
+    }
+
+    #[test]
+    fn probes_csv_error_line() {
+        let mut config = Config::default();
+        let error = config
+            .merge_probes_csv(
+                "mac,probe_index,name,offset\n00:11:22:aa:bb:cc,0,,\nfoo,1,,\n".as_bytes(),
+            )
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid MAC address on line 3");
+    }
+
+    #[test]
+    fn auth_credential() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            auth_credential = "2107ff"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.devices[&"00:11:22:aa:bb:cc".parse().unwrap()].auth_credential,
+            Some(vec![0x21, 0x07, 0xff])
+        );
+
+        assert!(toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            auth_credential = "21x7"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn config_unit_fahrenheit() {
+        let mut config = toml::from_str::<Config>(
+            r#"
+            config_unit = "F"
+            [simulate]
+            ambient_temperature = 68.0
+            [device."00:11:22:aa:bb:cc"]
+            probe_offsets = [9.0]
+            probe_alarms = [{ alarm_low = 212.0 }, {}]
+            "#,
+        )
+        .unwrap();
+        config.convert_config_unit();
+        assert_eq!(config.simulate.ambient_temperature, 20.0);
+        let device = &config.devices[&"00:11:22:aa:bb:cc".parse().unwrap()];
+        assert_eq!(device.probe_offsets, vec![5.0]);
+        assert_eq!(
+            device.probe_alarms,
+            vec![
+                ProbeAlarmConfig {
+                    alarm_low: Some(100.0),
+                    alarm_high: None,
+                },
+                ProbeAlarmConfig::default(),
+            ]
+        );
+    }
+
+    #[test]
+    fn probe_alarms_reversed() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            probe_alarms = [{ alarm_low = 130.0, alarm_high = 100.0 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "alarm_low 130 is not below alarm_high 100 for probe 0 of device 00:11:22:AA:BB:CC"
+        );
+    }
+
+    #[test]
+    fn device_base_template() {
+        let homie = HomieConfig::default();
+        assert_eq!(
+            homie.device_base("001122AABBCC", "Grill"),
+            "homie/cloudbbq-001122AABBCC"
+        );
+
+        let homie = HomieConfig {
+            device_base_template: "{prefix}/bbq/{name}/{mac}".to_owned(),
+            ..Default::default()
+        };
+        homie.validate().unwrap();
+        assert_eq!(
+            homie.device_base("001122AABBCC", "Big Grill"),
+            "homie/bbq/big-grill/001122AABBCC"
+        );
+
+        let homie = HomieConfig {
+            device_base_template: "{prefix}/{name}".to_owned(),
+            ..Default::default()
+        };
+        assert!(homie.validate().is_err());
+    }
+
+    #[test]
+    fn target_presets() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq.target_presets]
+            beef-medium = { max = 60.0 }
+            smoking = { mode = "hold", min = 105.0, max = 120.0 }
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.bbq.target_presets["beef-medium"],
+            TargetPreset {
+                mode: PresetMode::Single,
+                min: 0.0,
+                max: 60.0,
+            }
+        );
+        assert_eq!(
+            config.bbq.target_presets["smoking"],
+            TargetPreset {
+                mode: PresetMode::Hold,
+                min: 105.0,
+                max: 120.0,
+            }
+        );
+
+        let config = toml::from_str::<Config>(
+            r#"
+            [bbq.target_presets]
+            smoking = { mode = "range", min = 120.0, max = 105.0 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "min 120 is not below max 105 for target preset smoking"
+        );
+    }
+
+    #[test]
+    fn battery_curve_not_increasing() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            battery_curve = [{ voltage = 4200, percentage = 100 }, { voltage = 3300, percentage = 0 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "battery_curve voltages must increase, but 4200 is followed by 3300 for device 00:11:22:AA:BB:CC"
+        );
+    }
+
+    #[test]
+    fn device_mqtt_override() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc".mqtt]
+            host = "localhost"
+            use_tls = true
+            "#,
+        )
+        .unwrap();
+        let mqtt = config.devices[&"00:11:22:aa:bb:cc".parse().unwrap()]
+            .mqtt
+            .as_ref()
+            .unwrap();
+        assert_eq!(mqtt.host, "localhost");
+        assert!(mqtt.use_tls);
+        assert_eq!(mqtt.port, DEFAULT_PORT);
+    }
+
+    #[test]
+    fn labels() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [labels]
+            battery = "Batterie"
+            "battery/voltage" = "Spannung"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.node_label("battery", "Battery"), "Batterie");
+        assert_eq!(config.node_label("settings", "Settings"), "Settings");
+        assert_eq!(
+            config.property_label("battery", "voltage", "Voltage"),
+            "Spannung"
+        );
+        assert_eq!(
+            config.property_label("battery", "percentage", "Percentage"),
+            "Percentage"
+        );
+    }
+
+    #[test]
+    fn retained() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [retained]
+            "settings/alarm" = true
+            "settings/unit" = false
+            "#,
+        )
+        .unwrap();
+        assert!(config.property_retained("settings", "alarm", false));
+        assert!(!config.property_retained("settings", "unit", true));
+        assert!(config.property_retained("battery", "voltage", true));
+    }
+
+    #[test]
+    fn realtime_unit() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [device."00:11:22:aa:bb:cc"]
+            realtime_unit = "display"
+            [device."00:11:22:aa:bb:dd"]
+            "#,
+        )
+        .unwrap();
+        let display = config.devices[&"00:11:22:aa:bb:cc".parse().unwrap()].realtime_unit;
+        assert_eq!(display, RealTimeUnit::Display);
+        assert_eq!(
+            display.unit(TemperatureUnit::Fahrenheit),
+            TemperatureUnit::Fahrenheit
+        );
+        assert_eq!(
+            display.unit(TemperatureUnit::Celcius),
+            TemperatureUnit::Celcius
+        );
+        let default = config.devices[&"00:11:22:aa:bb:dd".parse().unwrap()].realtime_unit;
+        assert_eq!(default, RealTimeUnit::Celsius);
+        assert_eq!(
+            default.unit(TemperatureUnit::Fahrenheit),
+            TemperatureUnit::Celcius
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let mac_address: MacAddress = "00:11:22:aa:bb:cc".parse().unwrap();
+        let config = Config::builder()
+            .mqtt_host("broker.local")
+            .mqtt_port(8883)
+            .mqtt_tls(true)
+            .mqtt_credentials("user", "secret")
+            .homie_prefix("devices")
+            .device_id_prefix("grill")
+            .device(
+                mac_address,
+                DeviceConfig {
+                    name: Some("Grill".to_owned()),
+                    ..Default::default()
+                },
+            )
+            .build()
+            .unwrap();
+        assert_eq!(config.mqtt.host, "broker.local");
+        assert_eq!(config.mqtt.port, 8883);
+        assert!(config.mqtt.use_tls);
+        assert_eq!(config.mqtt.username.as_deref(), Some("user"));
+        assert_eq!(config.mqtt.password.as_deref(), Some("secret"));
+        assert_eq!(
+            config.homie.device_base("001122AABBCC", "Grill"),
+            "devices/grill-001122AABBCC"
+        );
+        assert_eq!(
+            config.device_config(&mac_address).unwrap().name.as_deref(),
+            Some("Grill")
+        );
+        // Everything else is the same as for an empty config file.
+        assert_eq!(config.bbq.publish_delta, BbqConfig::default().publish_delta);
+
+        assert!(Config::builder().homie_prefix("").build().is_err());
+        assert!(Config::builder().device_id_prefix("").build().is_err());
+    }
+
+    #[test]
+    fn builder_device_password_file() {
+        let path = temp_dir().join("cloudbbq-homie-test-builder-password");
+        fs::write(&path, "secret\n").unwrap();
+        let mac_address: MacAddress = "00:11:22:aa:bb:cc".parse().unwrap();
+        let device_config = DeviceConfig {
+            mqtt: Some(MqttConfig {
+                password_file: Some(path.to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let config = Config::builder()
+            .device(mac_address, device_config.clone())
+            .build()
+            .unwrap();
+        let mqtt_config = config.device_config(&mac_address).unwrap().mqtt.as_ref();
+        assert_eq!(mqtt_config.unwrap().password.as_deref(), Some("secret"));
+        fs::remove_file(&path).unwrap();
+
+        // The file must exist by the time the config is built.
+        assert!(Config::builder()
+            .device(mac_address, device_config)
+            .build()
+            .is_err());
+    }
+
     /// Parsing an empty config file should not give any errors.
     #[test]
     fn empty_config() {
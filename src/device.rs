@@ -0,0 +1,115 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use cloudbbq::{BBQDevice, RealTimeData, SettingResult, TemperatureUnit};
+use eyre::{bail, Report};
+use futures::stream::{BoxStream, StreamExt};
+use std::fmt::Debug;
+use std::future::Future;
+use std::ops::Range;
+
+/// The operations which `Bbq` needs from a thermometer, so that it can be backed either by a real
+/// Bluetooth device or by a simulation.
+pub trait Thermometer: Clone + Debug + Send + Sync + 'static {
+    /// Configure which temperature unit the thermometer uses for its display.
+    fn set_temperature_unit(
+        &self,
+        unit: TemperatureUnit,
+    ) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Set the desired temperature range for the given probe.
+    fn set_target_range(
+        &self,
+        probe: u8,
+        range: Range<f32>,
+    ) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Set the target temperature for the given probe.
+    fn set_target_temp(
+        &self,
+        probe: u8,
+        target: f32,
+    ) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Remove the target temperature setting for the given probe.
+    fn remove_target(&self, probe: u8) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Enable or disable sending real-time temperature data.
+    fn enable_real_time_data(
+        &self,
+        enable: bool,
+    ) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Request that the thermometer report its current battery level as a `SettingResult`.
+    fn request_battery_level(&self) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// Silence the alarm, if it is currently sounding.
+    fn silence_alarm(&self) -> impl Future<Output = Result<(), Report>> + Send;
+
+    /// The alarm tones which the thermometer supports choosing between. This is empty if the
+    /// thermometer doesn't support choosing an alarm tone, which is the case for all known real
+    /// devices so far.
+    fn alarm_tones(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Choose one of the alarm tones returned by `alarm_tones`.
+    fn set_alarm_tone(&self, tone: &str) -> impl Future<Output = Result<(), Report>> + Send {
+        let tone = tone.to_owned();
+        async move { bail!("Alarm tone {:?} not supported", tone) }
+    }
+
+    /// Sound the alarm. This isn't supported by any known real devices so far.
+    fn sound_alarm(&self) -> impl Future<Output = Result<(), Report>> + Send {
+        async { bail!("Sounding the alarm not supported") }
+    }
+
+    /// Get a stream of real-time temperature data.
+    fn real_time(
+        &self,
+    ) -> impl Future<Output = Result<BoxStream<'static, RealTimeData>, Report>> + Send;
+
+    /// Get a stream of setting results.
+    fn setting_results(
+        &self,
+    ) -> impl Future<Output = Result<BoxStream<'static, SettingResult>, Report>> + Send;
+}
+
+impl Thermometer for BBQDevice {
+    async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Report> {
+        Ok(BBQDevice::set_temperature_unit(self, unit).await?)
+    }
+
+    async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Report> {
+        Ok(BBQDevice::set_target_range(self, probe, range).await?)
+    }
+
+    async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Report> {
+        Ok(BBQDevice::set_target_temp(self, probe, target).await?)
+    }
+
+    async fn remove_target(&self, probe: u8) -> Result<(), Report> {
+        Ok(BBQDevice::remove_target(self, probe).await?)
+    }
+
+    async fn enable_real_time_data(&self, enable: bool) -> Result<(), Report> {
+        Ok(BBQDevice::enable_real_time_data(self, enable).await?)
+    }
+
+    async fn request_battery_level(&self) -> Result<(), Report> {
+        Ok(BBQDevice::request_battery_level(self).await?)
+    }
+
+    async fn silence_alarm(&self) -> Result<(), Report> {
+        Ok(BBQDevice::silence_alarm(self).await?)
+    }
+
+    async fn real_time(&self) -> Result<BoxStream<'static, RealTimeData>, Report> {
+        Ok(BBQDevice::real_time(self).await?.boxed())
+    }
+
+    async fn setting_results(&self) -> Result<BoxStream<'static, SettingResult>, Report> {
+        Ok(BBQDevice::setting_results(self).await?.boxed())
+    }
+}
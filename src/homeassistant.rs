@@ -0,0 +1,184 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Publishes Home Assistant MQTT discovery config for the properties of each device, so that Home
+//! Assistant can use them without its Homie integration.
+
+use eyre::Report;
+use rumqttc::{AsyncClient, ConnectionError, Event, MqttOptions, Outgoing, QoS};
+use serde_derive::Serialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{sleep, timeout};
+
+/// How long to wait before reconnecting after the connection to the broker fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for the discovery config to be removed when shutting down.
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Marks the entities as unavailable unless the Homie device is ready.
+const AVAILABILITY_TEMPLATE: &str = "{{ 'online' if value == 'ready' else 'offline' }}";
+
+/// A connection to the MQTT broker for publishing the discovery config of a single Homie device.
+#[derive(Debug)]
+pub struct HomeAssistantDiscovery {
+    client: AsyncClient,
+    event_loop: JoinHandle<()>,
+    qos: QoS,
+    /// The base topic of the Homie device, which its properties are published under.
+    device_base: String,
+    /// The topic under which to publish the config of each entity of the device.
+    config_base: String,
+    device: DeviceConfig,
+    /// The config topics which have been published, so they can be removed on shutdown.
+    published: Mutex<HashSet<String>>,
+}
+
+impl HomeAssistantDiscovery {
+    /// Connect to the MQTT broker to publish discovery config for the Homie device with the given
+    /// ID, base topic and name.
+    pub fn connect(
+        mqtt_options: MqttOptions,
+        qos: QoS,
+        discovery_prefix: &str,
+        device_id: &str,
+        device_base: &str,
+        name: &str,
+    ) -> Self {
+        let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+        let event_loop = task::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect))
+                    | Err(ConnectionError::RequestsDone) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("Home Assistant discovery connection failed: {}", e);
+                        sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+        Self {
+            client,
+            event_loop,
+            qos,
+            device_base: device_base.to_owned(),
+            config_base: format!("{}/sensor/{}", discovery_prefix, device_id),
+            device: DeviceConfig {
+                identifiers: vec![device_id.to_owned()],
+                name: name.to_owned(),
+                sw_version: env!("CARGO_PKG_VERSION"),
+            },
+            published: Default::default(),
+        }
+    }
+
+    /// Publish the config of a sensor entity for the given Homie property.
+    pub async fn publish_sensor(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        name: &str,
+        device_class: Option<&'static str>,
+        unit: Option<&str>,
+    ) -> Result<(), Report> {
+        let config = SensorConfig {
+            name: name.to_owned(),
+            unique_id: format!("{}_{}_{}", self.device.identifiers[0], node_id, property_id),
+            state_topic: format!("{}/{}/{}", self.device_base, node_id, property_id),
+            availability_topic: format!("{}/$state", self.device_base),
+            availability_template: AVAILABILITY_TEMPLATE,
+            device_class,
+            unit_of_measurement: unit.map(ToOwned::to_owned),
+            device: self.device.clone(),
+        };
+        let topic = self.config_topic(node_id, property_id);
+        self.published.lock().unwrap().insert(topic.clone());
+        self.client
+            .publish(topic, self.qos, true, serde_json::to_string(&config)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the config of the sensor entity for the given Homie property, if it was published.
+    pub async fn remove_sensor(&self, node_id: &str, property_id: &str) -> Result<(), Report> {
+        let topic = self.config_topic(node_id, property_id);
+        if self.published.lock().unwrap().remove(&topic) {
+            self.client.publish(topic, self.qos, true, "").await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the config of every entity which was published, disconnect from the broker, and wait
+    /// a little while for that to be sent.
+    pub async fn remove_all(self) {
+        let topics: Vec<String> = self.published.lock().unwrap().drain().collect();
+        for topic in topics {
+            if let Err(e) = self.client.publish(&topic, self.qos, true, "").await {
+                log::warn!("Failed to remove Home Assistant config {}: {}", topic, e);
+            }
+        }
+        if let Err(e) = self.client.disconnect().await {
+            log::warn!("Failed to disconnect Home Assistant discovery: {}", e);
+            return;
+        }
+        let _ = timeout(REMOVE_TIMEOUT, self.event_loop).await;
+    }
+
+    fn config_topic(&self, node_id: &str, property_id: &str) -> String {
+        format!("{}/{}_{}/config", self.config_base, node_id, property_id)
+    }
+}
+
+/// The Home Assistant device which all the entities of a Homie device belong to.
+#[derive(Clone, Debug, Serialize)]
+struct DeviceConfig {
+    identifiers: Vec<String>,
+    name: String,
+    sw_version: &'static str,
+}
+
+/// The discovery config of a Home Assistant MQTT sensor.
+#[derive(Clone, Debug, Serialize)]
+struct SensorConfig {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    availability_topic: String,
+    availability_template: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<String>,
+    device: DeviceConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensor_config_json() {
+        let config = SensorConfig {
+            name: "Probe 1".to_owned(),
+            unique_id: "cloudbbq-001122aabbcc_probe0_temperature".to_owned(),
+            state_topic: "homie/cloudbbq-001122aabbcc/probe0/temperature".to_owned(),
+            availability_topic: "homie/cloudbbq-001122aabbcc/$state".to_owned(),
+            availability_template: AVAILABILITY_TEMPLATE,
+            device_class: Some("temperature"),
+            unit_of_measurement: Some("°C".to_owned()),
+            device: DeviceConfig {
+                identifiers: vec!["cloudbbq-001122aabbcc".to_owned()],
+                name: "Grill".to_owned(),
+                sw_version: "1.0.0",
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&config).unwrap(),
+            r#"{"name":"Probe 1","unique_id":"cloudbbq-001122aabbcc_probe0_temperature","state_topic":"homie/cloudbbq-001122aabbcc/probe0/temperature","availability_topic":"homie/cloudbbq-001122aabbcc/$state","availability_template":"{{ 'online' if value == 'ready' else 'offline' }}","device_class":"temperature","unit_of_measurement":"°C","device":{"identifiers":["cloudbbq-001122aabbcc"],"name":"Grill","sw_version":"1.0.0"}}"#
+        );
+    }
+}
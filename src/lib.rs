@@ -0,0 +1,51 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Bridge from Bluetooth barbecue thermometers to MQTT, following the Homie convention.
+//!
+//! This is used by the `cloudbbq-homie` binary, but may also be embedded in another program by
+//! reading a [`Config`] or building one with [`Config::builder`], and calling [`run_system`].
+//!
+//! To run a single thermometer rather than discovering them, create a [`Bbq`] and call
+//! [`Bbq::run`], which takes the channels connecting it to the rest of the bridge.
+//!
+//! # Custom authentication
+//!
+//! Some thermometers need a different authentication handshake after connecting to the one which
+//! the `cloudbbq` crate implements. To support them, implement [`Authenticator`] and pass it to
+//! [`Bbq::connect_with_authenticator`], which tries it before falling back to the default
+//! handshake. [`CredentialAuthenticator`] covers the common case of a device which just expects a
+//! different pre-shared credential, and is what the `auth_credential` device config option uses.
+
+mod auth;
+mod bbq;
+mod bridge;
+mod config;
+mod device;
+mod homeassistant;
+mod logging;
+mod metrics;
+mod notify;
+mod persist;
+mod push;
+mod simulate;
+mod system;
+mod systemd;
+mod units;
+
+pub use crate::auth::{Authenticator, CredentialAuthenticator};
+pub use crate::bbq::Bbq;
+pub use crate::bridge::BridgeCommand;
+pub use crate::config::{
+    get_tls_client_config, BbqConfig, Config, ConfigBuilder, DeviceConfig, LogFormat,
+    DEFAULT_CONFIG_FILENAME,
+};
+pub use crate::device::Thermometer;
+pub use crate::logging::format_json;
+pub use crate::metrics::Metrics;
+pub use crate::notify::{AlarmReason, Notification};
+pub use crate::push::Reading;
+pub use crate::simulate::SimulatedDevice;
+pub use crate::system::{list_devices, run_simulation, run_system, test_mqtt};
+pub use crate::systemd::run_watchdog;
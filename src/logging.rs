@@ -0,0 +1,75 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Formats log records as JSON lines, for log collectors such as Loki.
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use pretty_env_logger::env_logger::fmt::Formatter;
+use serde_json::{Map, Number};
+use std::io::{self, Write};
+
+/// Write the given record as a single line of JSON, with its structured key-values as extra fields.
+pub fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let timestamp = buf.timestamp_millis().to_string();
+    writeln!(buf, "{}", json_line(&timestamp, record))
+}
+
+fn json_line(timestamp: &str, record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_owned(), timestamp.into());
+    fields.insert("level".to_owned(), record.level().as_str().into());
+    fields.insert("target".to_owned(), record.target().into());
+    fields.insert("message".to_owned(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut FieldVisitor(&mut fields));
+    serde_json::Value::Object(fields).to_string()
+}
+
+/// Adds each key-value of a record to a JSON object, keeping numbers and booleans as such.
+struct FieldVisitor<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64().and_then(Number::from_f64) {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn json_fields() {
+        let key_values: &[(&str, Value)] = &[
+            ("mac", Value::from("00:11:22:AA:BB:CC")),
+            ("probe", Value::from(1u8)),
+            ("temperature", Value::from(21.5f32)),
+        ];
+        assert_eq!(
+            json_line(
+                "2021-06-01T12:00:00.000Z",
+                &Record::builder()
+                    .args(format_args!("Probe {} is at {}", 2, 21.5))
+                    .level(Level::Debug)
+                    .target("cloudbbq_homie::bbq")
+                    .key_values(&key_values)
+                    .build()
+            ),
+            r#"{"level":"DEBUG","mac":"00:11:22:AA:BB:CC","message":"Probe 2 is at 21.5","probe":1,"target":"cloudbbq_homie::bbq","temperature":21.5,"timestamp":"2021-06-01T12:00:00.000Z"}"#
+        );
+    }
+}
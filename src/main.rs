@@ -2,68 +2,109 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
-mod bbq;
-mod config;
-
-use crate::bbq::Bbq;
-use crate::config::{get_tls_client_config, Config};
 use bluez_async::BluetoothSession;
-use cloudbbq::find_devices;
-use eyre::{bail, Report};
-use futures::future::try_join_all;
-use futures::TryFutureExt;
-use rustls::ClientConfig;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::{task, time, try_join};
+use clap::{Parser, Subcommand};
+use cloudbbq_homie::{
+    format_json, get_tls_client_config, list_devices, run_simulation, run_system, run_watchdog,
+    test_mqtt, Config, LogFormat, DEFAULT_CONFIG_FILENAME,
+};
+use eyre::{bail, Report, WrapErr};
+use std::env;
+use std::future;
+use tokio::select;
+
+/// Bridge from Bluetooth barbecue thermometers to MQTT, following the Homie convention.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Publish data from simulated thermometers rather than real Bluetooth devices.
+    #[arg(long)]
+    simulate: bool,
+    /// Scan for thermometers, print their MAC addresses and names, then exit.
+    #[arg(long)]
+    list_devices: bool,
+    /// Publish one set of readings from each thermometer, then disconnect and exit.
+    #[arg(long)]
+    once: bool,
+    /// The config file to use.
+    #[arg(long, env = "CLOUDBBQ_HOMIE_CONFIG", default_value = DEFAULT_CONFIG_FILENAME)]
+    config: String,
+    /// Notify systemd once devices have been discovered, and ping its watchdog if it is enabled.
+    #[arg(long, env = "CLOUDBBQ_HOMIE_SYSTEMD_NOTIFY")]
+    systemd_notify: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-const SCAN_DURATION: Duration = Duration::from_secs(5);
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Check that the MQTT broker can be connected to and published to, then exit.
+    TestMqtt,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
     stable_eyre::install()?;
-    pretty_env_logger::init();
     color_backtrace::install();
 
-    let config = Config::from_file()?;
-    let tls_client_config = get_tls_client_config(&config.mqtt);
+    let args = Args::parse();
+    let config = Config::read(&args.config)?;
+    init_logger(&config);
+    let tls_client_config =
+        get_tls_client_config(&config.mqtt).wrap_err("Failed to set up TLS for [mqtt]")?;
+
+    if let Some(Command::TestMqtt) = args.command {
+        return test_mqtt(&config, tls_client_config).await;
+    }
+    if args.simulate {
+        return run_simulation(&config, tls_client_config, args.once).await;
+    }
 
     // Connect a Bluetooth session.
     let (dbus_handle, session) = BluetoothSession::new().await?;
 
-    let bbq_handle = run_system(&config, tls_client_config, &session);
+    if args.list_devices {
+        return list_devices(&config, &session).await;
+    }
 
-    // Poll everything to completion, until the first one bombs out.
-    let res: Result<_, Report> = try_join! {
-        // If this ever finishes, we lost connection to D-Bus.
-        dbus_handle.err_into(),
-        bbq_handle.err_into(),
+    let bbq_handle = run_system(
+        &config,
+        tls_client_config,
+        &session,
+        args.systemd_notify,
+        args.once,
+    );
+    let watchdog = async {
+        if args.systemd_notify {
+            run_watchdog(session.clone()).await
+        } else {
+            future::pending().await
+        }
     };
-    res?;
 
-    Ok(())
+    // Run until the devices are shut down, or something bombs out.
+    select! {
+        // If this ever finishes, we lost connection to D-Bus.
+        res = dbus_handle => {
+            res?;
+            bail!("Lost connection to D-Bus");
+        }
+        res = bbq_handle => res,
+        res = watchdog => res,
+    }
 }
 
-async fn run_system(
-    config: &Config,
-    tls_client_config: Option<Arc<ClientConfig>>,
-    session: &BluetoothSession,
-) -> Result<(), Report> {
-    log::info!("Starting discovery");
-    session.start_discovery().await?;
-    time::sleep(SCAN_DURATION).await;
-    let devices = find_devices(session).await?;
-    if devices.is_empty() {
-        bail!("No devices found");
+/// Initialise logging, with the filter from the `RUST_LOG` environment variable if it is set, or
+/// else from the config file. Logs are written as text unless JSON is configured.
+fn init_logger(config: &Config) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if config.log_format == LogFormat::Json {
+        builder.format(format_json);
     }
-
-    let mut join_handles = vec![];
-    for device in devices {
-        let bbq = Bbq::connect(session, device, config.to_owned()).await?;
-        let handle = task::spawn(bbq.run(tls_client_config.clone()));
-        join_handles.push(handle);
+    if let Ok(filter) = env::var("RUST_LOG") {
+        builder.parse_filters(&filter);
+    } else if let Some(filter) = &config.log_filter {
+        builder.parse_filters(filter);
     }
-    try_join_all(join_handles).await?;
-
-    Ok(())
+    builder.init();
 }
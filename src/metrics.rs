@@ -0,0 +1,226 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Serves the latest readings from every device over HTTP for Prometheus to scrape.
+
+use bluez_async::MacAddress;
+use eyre::{Report, WrapErr};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+/// The latest metrics for every device which has been connected.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    devices: Mutex<BTreeMap<MacAddress, DeviceMetrics>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DeviceMetrics {
+    name: String,
+    connected: bool,
+    battery_percentage: Option<u32>,
+    /// The temperature in ºC of each connected probe, by index.
+    probe_temperatures: BTreeMap<u8, f32>,
+}
+
+impl Metrics {
+    /// Record that the given device is connected. It is marked as disconnected again when the
+    /// returned guard is dropped.
+    pub fn connected(self: &Arc<Self>, mac_address: MacAddress, name: &str) -> ConnectedGuard {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(mac_address).or_default();
+        device.name = name.to_owned();
+        device.connected = true;
+        ConnectedGuard {
+            metrics: self.clone(),
+            mac_address,
+        }
+    }
+
+    pub fn set_probe_temperature(
+        &self,
+        mac_address: MacAddress,
+        probe_index: u8,
+        temperature: f32,
+    ) {
+        self.update(mac_address, |device| {
+            device.probe_temperatures.insert(probe_index, temperature);
+        });
+    }
+
+    pub fn remove_probe(&self, mac_address: MacAddress, probe_index: u8) {
+        self.update(mac_address, |device| {
+            device.probe_temperatures.remove(&probe_index);
+        });
+    }
+
+    pub fn set_battery_percentage(&self, mac_address: MacAddress, percentage: u32) {
+        self.update(mac_address, |device| {
+            device.battery_percentage = Some(percentage);
+        });
+    }
+
+    fn update(&self, mac_address: MacAddress, f: impl FnOnce(&mut DeviceMetrics)) {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(&mac_address) {
+            f(device);
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let mut connected = String::new();
+        let mut battery = String::new();
+        let mut temperatures = String::new();
+        for (mac_address, device) in devices.iter() {
+            let labels = format!(
+                "mac=\"{}\",device=\"{}\"",
+                mac_address,
+                escape_label(&device.name)
+            );
+            writeln!(
+                connected,
+                "cloudbbq_connected{{{}}} {}",
+                labels,
+                u8::from(device.connected)
+            )
+            .unwrap();
+            if let Some(percentage) = device.battery_percentage {
+                writeln!(
+                    battery,
+                    "cloudbbq_battery_percentage{{{}}} {}",
+                    labels, percentage
+                )
+                .unwrap();
+            }
+            for (probe_index, temperature) in &device.probe_temperatures {
+                writeln!(
+                    temperatures,
+                    "cloudbbq_probe_temperature_celsius{{{},probe=\"{}\"}} {}",
+                    labels, probe_index, temperature
+                )
+                .unwrap();
+            }
+        }
+        format!(
+            "# HELP cloudbbq_connected Whether the device is currently connected.\n\
+             # TYPE cloudbbq_connected gauge\n\
+             {}\
+             # HELP cloudbbq_battery_percentage The battery level of the device.\n\
+             # TYPE cloudbbq_battery_percentage gauge\n\
+             {}\
+             # HELP cloudbbq_probe_temperature_celsius The temperature of the probe.\n\
+             # TYPE cloudbbq_probe_temperature_celsius gauge\n\
+             {}",
+            connected, battery, temperatures
+        )
+    }
+}
+
+/// Marks a device as disconnected when it is dropped, and forgets its readings.
+#[derive(Debug)]
+pub struct ConnectedGuard {
+    metrics: Arc<Metrics>,
+    mac_address: MacAddress,
+}
+
+impl Drop for ConnectedGuard {
+    fn drop(&mut self) {
+        self.metrics.update(self.mac_address, |device| {
+            device.connected = false;
+            device.battery_percentage = None;
+            device.probe_temperatures.clear();
+        });
+    }
+}
+
+/// Escape a value for use in a label, as required by the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Listen on the given address and respond to every HTTP request with the current metrics.
+pub async fn serve_metrics(address: SocketAddr, metrics: Arc<Metrics>) -> Result<(), Report> {
+    let listener = TcpListener::bind(address)
+        .await
+        .wrap_err_with(|| format!("Failed to listen for metrics requests on {}", address))?;
+    log::info!("Serving metrics on http://{}/metrics", address);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        task::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                log::warn!("Failed to respond to metrics request from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Read a request, which is assumed to be a GET as there is nothing else to serve, and write the
+/// metrics in response.
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Report> {
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request).await?;
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let mac_address: MacAddress = "00:11:22:aa:bb:cc".parse().unwrap();
+        let guard = metrics.connected(mac_address, "Grill \"1\"");
+        metrics.set_battery_percentage(mac_address, 80);
+        metrics.set_probe_temperature(mac_address, 0, 21.5);
+        metrics.set_probe_temperature(mac_address, 1, 60.0);
+        metrics.remove_probe(mac_address, 1);
+        assert_eq!(
+            metrics.render(),
+            "# HELP cloudbbq_connected Whether the device is currently connected.\n\
+             # TYPE cloudbbq_connected gauge\n\
+             cloudbbq_connected{mac=\"00:11:22:AA:BB:CC\",device=\"Grill \\\"1\\\"\"} 1\n\
+             # HELP cloudbbq_battery_percentage The battery level of the device.\n\
+             # TYPE cloudbbq_battery_percentage gauge\n\
+             cloudbbq_battery_percentage{mac=\"00:11:22:AA:BB:CC\",device=\"Grill \\\"1\\\"\"} 80\n\
+             # HELP cloudbbq_probe_temperature_celsius The temperature of the probe.\n\
+             # TYPE cloudbbq_probe_temperature_celsius gauge\n\
+             cloudbbq_probe_temperature_celsius{mac=\"00:11:22:AA:BB:CC\",device=\"Grill \\\"1\\\"\",probe=\"0\"} 21.5\n"
+        );
+
+        drop(guard);
+        assert_eq!(
+            metrics.devices.lock().unwrap()[&mac_address],
+            DeviceMetrics {
+                name: "Grill \"1\"".to_owned(),
+                connected: false,
+                battery_percentage: None,
+                probe_temperatures: BTreeMap::new(),
+            }
+        );
+    }
+}
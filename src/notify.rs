@@ -0,0 +1,139 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Calls a webhook when a device alarms, such as to send a push notification with ntfy.
+
+use crate::config::{NotifyConfig, NotifyFormat};
+use eyre::Report;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Client;
+use serde_derive::Serialize;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use url::Url;
+
+/// The number of notifications to queue while waiting for earlier requests to finish.
+const QUEUE_SIZE: usize = 100;
+
+/// Why a device alarmed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmReason {
+    /// A probe reached its target, so the device alarm sounded.
+    TargetReached,
+    /// A probe dropped below its low alarm threshold.
+    Low,
+    /// A probe rose above its high alarm threshold.
+    High,
+    /// The cook has been running for longer than the configured maximum.
+    CookTimeout,
+}
+
+/// A single alarm to notify about.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// The name of the device.
+    pub device: String,
+    /// The index of the probe which alarmed, if the alarm is for a probe.
+    pub probe: Option<u8>,
+    pub reason: AlarmReason,
+    /// The temperature of the probe in ºC, if the alarm is for a probe.
+    pub temperature: Option<f32>,
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.device)?;
+        if let Some(probe) = self.probe {
+            write!(f, " probe {}", probe + 1)?;
+        }
+        match self.reason {
+            AlarmReason::TargetReached => write!(f, " reached its target")?,
+            AlarmReason::Low => write!(f, " is below its alarm threshold")?,
+            AlarmReason::High => write!(f, " is above its alarm threshold")?,
+            AlarmReason::CookTimeout => write!(f, " has exceeded the maximum cook duration")?,
+        }
+        if let Some(temperature) = self.temperature {
+            write!(f, " at {}ºC", temperature)?;
+        }
+        Ok(())
+    }
+}
+
+/// Create the channel for devices to send notifications on, if a webhook is configured, along with
+/// the task to send them.
+pub fn notify_channel(
+    config: &NotifyConfig,
+) -> Option<(
+    Sender<Notification>,
+    impl Future<Output = Result<(), Report>>,
+)> {
+    let url = config.url.clone()?;
+    let (sender, receiver) = channel(QUEUE_SIZE);
+    Some((sender, run_notify(url, config.clone(), receiver)))
+}
+
+/// POST each notification received on `notifications` to the given URL, until all senders are
+/// dropped. Failures are logged and the notification dropped.
+async fn run_notify(
+    url: Url,
+    config: NotifyConfig,
+    mut notifications: Receiver<Notification>,
+) -> Result<(), Report> {
+    let client = Client::new();
+    while let Some(notification) = notifications.recv().await {
+        let mut request = client.post(url.clone());
+        request = match config.format {
+            NotifyFormat::Json => request.json(&notification),
+            NotifyFormat::Text => request.body(notification.to_string()),
+        };
+        if let Some(authorization) = &config.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        match request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => log::trace!("Sent notification: {}", notification),
+            Err(e) => log::warn!("Failed to send notification '{}': {}", notification, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_formats() {
+        let notification = Notification {
+            device: "Grill".to_owned(),
+            probe: Some(1),
+            reason: AlarmReason::TargetReached,
+            temperature: Some(74.5),
+        };
+        assert_eq!(
+            serde_json::to_string(&notification).unwrap(),
+            r#"{"device":"Grill","probe":1,"reason":"target_reached","temperature":74.5}"#
+        );
+        assert_eq!(
+            notification.to_string(),
+            "Grill probe 2 reached its target at 74.5ºC"
+        );
+
+        let notification = Notification {
+            device: "Grill".to_owned(),
+            probe: None,
+            reason: AlarmReason::CookTimeout,
+            temperature: None,
+        };
+        assert_eq!(
+            notification.to_string(),
+            "Grill has exceeded the maximum cook duration"
+        );
+    }
+}
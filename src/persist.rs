@@ -0,0 +1,102 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Saves state for each device to a JSON file, so that it survives the bridge restarting.
+
+use bluez_async::MacAddress;
+use eyre::{Report, WrapErr};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs::{read_to_string, rename, write};
+use std::io::ErrorKind;
+use std::sync::Mutex;
+
+/// Held while the state file is being updated, so that devices saving at the same time don't
+/// overwrite each other's changes.
+static STATE_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Load the state saved for the given device, or the default if there is none. A missing or corrupt
+/// file is treated as having no saved state.
+pub fn load_state<T: Default + DeserializeOwned>(path: &str, mac_address: MacAddress) -> T {
+    let _lock = STATE_FILE_LOCK.lock().unwrap();
+    let result = read_states(path).and_then(|mut states| {
+        states
+            .remove(&mac_address.to_string())
+            .map(serde_json::from_value)
+            .transpose()
+            .wrap_err_with(|| format!("Invalid state for {}", mac_address))
+    });
+    match result {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Failed to load state from {}: {:?}", path, e);
+            T::default()
+        }
+    }
+}
+
+/// Save the state for the given device, leaving that of other devices in the file as it was.
+pub fn save_state(
+    path: &str,
+    mac_address: MacAddress,
+    state: &impl Serialize,
+) -> Result<(), Report> {
+    let _lock = STATE_FILE_LOCK.lock().unwrap();
+    // Start again rather than failing forever if the file is corrupt.
+    let mut states = read_states(path).unwrap_or_default();
+    states.insert(mac_address.to_string(), serde_json::to_value(state)?);
+    // Write to a temporary file and then rename it, so that the file isn't left half written if the
+    // bridge is killed.
+    let temporary_path = format!("{}.tmp", path);
+    write(&temporary_path, serde_json::to_string_pretty(&states)?)
+        .wrap_err_with(|| format!("Writing {}", temporary_path))?;
+    rename(&temporary_path, path).wrap_err_with(|| format!("Renaming to {}", path))?;
+    Ok(())
+}
+
+/// Read the map from MAC address to state for every device, which is empty if the file doesn't
+/// exist yet.
+fn read_states(path: &str) -> Result<Map<String, Value>, Report> {
+    match read_to_string(path) {
+        Ok(contents) => {
+            serde_json::from_str(&contents).wrap_err_with(|| format!("Parsing {}", path))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Map::new()),
+        Err(e) => Err(e).wrap_err_with(|| format!("Reading {}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_dir().join("cloudbbq-homie-persist-test.json");
+        let path = path.to_str().unwrap();
+        let _ = remove_file(path);
+        let first: MacAddress = "00:11:22:aa:bb:cc".parse().unwrap();
+        let second: MacAddress = "00:11:22:aa:bb:dd".parse().unwrap();
+
+        assert_eq!(load_state::<HashMap<u8, f32>>(path, first), HashMap::new());
+        save_state(path, first, &HashMap::from([(0u8, 60.5f32)])).unwrap();
+        save_state(path, second, &HashMap::from([(1u8, 70.0f32)])).unwrap();
+        assert_eq!(
+            load_state::<HashMap<u8, f32>>(path, first),
+            HashMap::from([(0, 60.5)])
+        );
+        assert_eq!(
+            load_state::<HashMap<u8, f32>>(path, second),
+            HashMap::from([(1, 70.0)])
+        );
+
+        write(path, "not JSON").unwrap();
+        assert_eq!(load_state::<HashMap<u8, f32>>(path, first), HashMap::new());
+        remove_file(path).unwrap();
+    }
+}
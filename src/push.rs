@@ -0,0 +1,106 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Mirrors temperature readings to a REST endpoint, for systems which don't speak MQTT.
+
+use crate::config::PushConfig;
+use eyre::Report;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Client;
+use serde_derive::Serialize;
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use url::Url;
+
+/// A single probe temperature reading to push.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reading {
+    /// The MAC address of the device.
+    pub device: String,
+    pub probe: u8,
+    /// The temperature in ºC.
+    pub temperature: f32,
+    /// When the reading was received, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Reading {
+    /// Create a reading received now.
+    pub fn new(device: String, probe: u8, temperature: f32) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            device,
+            probe,
+            temperature,
+            timestamp,
+        }
+    }
+}
+
+/// Create the channel for devices to send readings on, if pushing is configured, along with the
+/// task to push them.
+pub fn push_channel(
+    config: &PushConfig,
+) -> Option<(Sender<Reading>, impl Future<Output = Result<(), Report>>)> {
+    let url = config.url.clone()?;
+    let (sender, receiver) = channel(config.queue_size.max(1));
+    Some((sender, run_push(url, config.clone(), receiver)))
+}
+
+/// POST batches of readings received on `readings` to the given URL as JSON arrays, until all
+/// senders are dropped.
+///
+/// Failures are logged and the batch dropped, so that a slow or broken endpoint never holds up the
+/// devices; if readings arrive faster than they can be pushed they are dropped by the sender.
+async fn run_push(
+    url: Url,
+    config: PushConfig,
+    mut readings: Receiver<Reading>,
+) -> Result<(), Report> {
+    let client = Client::new();
+    let mut batch = vec![];
+    while readings
+        .recv_many(&mut batch, config.batch_size.max(1))
+        .await
+        > 0
+    {
+        let mut request = client.post(url.clone()).json(&batch);
+        if let Some(authorization) = &config.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        match request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => log::trace!("Pushed {} readings", batch.len()),
+            Err(e) => log::warn!("Failed to push {} readings: {}", batch.len(), e),
+        }
+        batch.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_json() {
+        let reading = Reading {
+            device: "00:11:22:AA:BB:CC".to_owned(),
+            probe: 1,
+            temperature: 62.5,
+            timestamp: 1234,
+        };
+        assert_eq!(
+            serde_json::to_string(&[reading]).unwrap(),
+            r#"[{"device":"00:11:22:AA:BB:CC","probe":1,"temperature":62.5,"timestamp":1234}]"#
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A simulated thermometer which generates fake data, for demos and development without hardware.
+
+use crate::config::SimulateConfig;
+use crate::device::Thermometer;
+use cloudbbq::{RealTimeData, SettingResult, TemperatureUnit};
+use eyre::Report;
+use futures::stream::{self, BoxStream, StreamExt};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// The maximum voltage reported by the simulated battery.
+const MAX_VOLTAGE: u16 = 6550;
+/// The period of the slow wobble added to simulated temperatures, in seconds.
+const WOBBLE_PERIOD: f32 = 300.0;
+/// The amplitude of the slow wobble added to simulated temperatures.
+const WOBBLE_AMPLITUDE: f32 = 2.0;
+/// How many readings the simulated alarm sounds for before somebody presses the button to silence
+/// it.
+const ALARM_READINGS: u32 = 5;
+/// The alarm tones which the simulated thermometer pretends to support.
+const ALARM_TONES: [&str; 2] = ["Beep", "Chime"];
+
+/// A fake thermometer producing plausible temperature curves, battery drain and alarms.
+#[derive(Clone, Debug)]
+pub struct SimulatedDevice {
+    config: SimulateConfig,
+    start: Instant,
+    state: Arc<Mutex<SimulationState>>,
+}
+
+#[derive(Debug, Default)]
+struct SimulationState {
+    real_time_enabled: bool,
+    setting_results: Option<UnboundedSender<SettingResult>>,
+    /// Map from probe index to target range.
+    targets: HashMap<u8, Range<f32>>,
+    /// The number of readings for which the alarm has been sounding, if it is.
+    alarm_readings: Option<u32>,
+}
+
+impl SimulatedDevice {
+    pub fn new(config: SimulateConfig) -> Self {
+        Self {
+            config,
+            start: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// Whether the simulated alarm is currently sounding.
+    #[cfg(test)]
+    pub fn alarm_sounding(&self) -> bool {
+        self.state.lock().unwrap().alarm_readings.is_some()
+    }
+
+    /// The simulated temperature of the given probe at the given time since the simulation started.
+    fn temperature(&self, probe: u8, elapsed: Duration) -> f32 {
+        let config = &self.config;
+        let elapsed = elapsed.as_secs_f32();
+        // Each probe heats up towards a different plateau, with some gentle variation on top.
+        let plateau = config.ambient_temperature
+            + (config.max_temperature - config.ambient_temperature)
+                * (1.0 - f32::from(probe) / (2.0 * f32::from(config.probes)));
+        let heating = 1.0 - (-elapsed / (config.heating_minutes * 60.0)).exp();
+        let wobble =
+            WOBBLE_AMPLITUDE * (2.0 * PI * elapsed / WOBBLE_PERIOD + f32::from(probe)).sin();
+        let temperature = config.ambient_temperature
+            + (plateau - config.ambient_temperature) * heating
+            + wobble * heating;
+        // Round to the resolution of real thermometers.
+        (temperature * 10.0).round() / 10.0
+    }
+
+    fn battery_level(&self) -> SettingResult {
+        let drained = self.start.elapsed().as_secs_f32() / (self.config.battery_hours * 3600.0);
+        let current_voltage = (f32::from(MAX_VOLTAGE) * (1.0 - drained).max(0.0)) as u16;
+        SettingResult::BatteryLevel {
+            current_voltage,
+            max_voltage: MAX_VOLTAGE,
+        }
+    }
+
+    /// Take a reading of all probes, or `None` if real-time data is disabled.
+    fn reading(&self) -> Option<RealTimeData> {
+        let elapsed = self.start.elapsed();
+        let mut state = self.state.lock().unwrap();
+        if !state.real_time_enabled {
+            return None;
+        }
+        let probe_temperatures: Vec<f32> = (0..self.config.probes)
+            .map(|probe| self.temperature(probe, elapsed))
+            .collect();
+
+        // Sound the alarm if any probe is outside its target range, and have somebody silence it
+        // after a few readings.
+        let out_of_range = probe_temperatures.iter().zip(0..).any(|(temperature, probe)| {
+            matches!(state.targets.get(&probe), Some(range) if !range.contains(temperature))
+        });
+        state.alarm_readings = match state.alarm_readings {
+            None if out_of_range => {
+                log::info!("Simulated alarm sounding");
+                Some(0)
+            }
+            Some(readings) if readings >= ALARM_READINGS => {
+                state.send_setting_result(SettingResult::SilencePressed);
+                None
+            }
+            Some(readings) => Some(readings + 1),
+            None => None,
+        };
+
+        Some(RealTimeData {
+            probe_temperatures: probe_temperatures.into_iter().map(Some).collect(),
+        })
+    }
+}
+
+impl SimulationState {
+    fn send_setting_result(&self, result: SettingResult) {
+        if let Some(setting_results) = &self.setting_results {
+            // It doesn't matter if nobody is listening any more.
+            let _ = setting_results.unbounded_send(result);
+        }
+    }
+}
+
+impl Thermometer for SimulatedDevice {
+    async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Report> {
+        log::info!("Simulated temperature unit set to {:?}", unit);
+        Ok(())
+    }
+
+    async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Report> {
+        self.state.lock().unwrap().targets.insert(probe, range);
+        Ok(())
+    }
+
+    async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Report> {
+        self.set_target_range(probe, f32::MIN..target).await
+    }
+
+    async fn remove_target(&self, probe: u8) -> Result<(), Report> {
+        self.state.lock().unwrap().targets.remove(&probe);
+        Ok(())
+    }
+
+    async fn enable_real_time_data(&self, enable: bool) -> Result<(), Report> {
+        self.state.lock().unwrap().real_time_enabled = enable;
+        Ok(())
+    }
+
+    async fn request_battery_level(&self) -> Result<(), Report> {
+        self.state
+            .lock()
+            .unwrap()
+            .send_setting_result(self.battery_level());
+        Ok(())
+    }
+
+    async fn silence_alarm(&self) -> Result<(), Report> {
+        self.state.lock().unwrap().alarm_readings = None;
+        Ok(())
+    }
+
+    async fn sound_alarm(&self) -> Result<(), Report> {
+        log::info!("Simulated alarm sounding");
+        self.state.lock().unwrap().alarm_readings = Some(0);
+        Ok(())
+    }
+
+    fn alarm_tones(&self) -> &'static [&'static str] {
+        &ALARM_TONES
+    }
+
+    async fn set_alarm_tone(&self, tone: &str) -> Result<(), Report> {
+        log::info!("Simulated alarm tone set to {}", tone);
+        Ok(())
+    }
+
+    async fn real_time(&self) -> Result<BoxStream<'static, RealTimeData>, Report> {
+        let device = self.clone();
+        let interval = Duration::from_secs(self.config.interval_seconds);
+        Ok(stream::unfold(device, move |device| async move {
+            loop {
+                sleep(interval).await;
+                if let Some(reading) = device.reading() {
+                    return Some((reading, device));
+                }
+            }
+        })
+        .boxed())
+    }
+
+    async fn setting_results(&self) -> Result<BoxStream<'static, SettingResult>, Report> {
+        let (sender, receiver) = unbounded();
+        self.state.lock().unwrap().setting_results = Some(sender);
+        Ok(receiver.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_curve() {
+        let device = SimulatedDevice::new(SimulateConfig::default());
+        assert_eq!(device.temperature(0, Duration::ZERO), 20.0);
+        // After a long time the first probe should have reached roughly the maximum temperature.
+        let hot = device.temperature(0, Duration::from_secs(24 * 3600));
+        assert!((88.0..=92.0).contains(&hot), "{}", hot);
+        // Later probes plateau lower.
+        assert!(device.temperature(3, Duration::from_secs(24 * 3600)) < hot);
+    }
+}
@@ -0,0 +1,593 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Discovers thermometers and runs them along with the rest of the bridge, reconnecting to them as
+//! needed.
+
+use crate::auth::BBQ_SERVICE_UUID;
+use crate::bbq::Bbq;
+use crate::bridge::{
+    forward_shutdown, run_bridge, shutdown_requested, BridgeChannels, BridgeCommand,
+};
+use crate::config::{get_mqtt_options, BbqConfig, Config};
+use crate::metrics::{serve_metrics, Metrics};
+use crate::notify::notify_channel;
+use crate::push::push_channel;
+use crate::systemd;
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoffBuilder;
+use bluez_async::{
+    uuid_from_u16, AdapterId, AdapterInfo, BluetoothSession, DeviceInfo, DiscoveryFilter,
+    MacAddress,
+};
+use cloudbbq::BBQDevice;
+use eyre::{bail, Report, WrapErr};
+use futures::future::try_join_all;
+use rumqttc::{AsyncClient, Event, Incoming, QoS};
+use rustls::ClientConfig;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::{select, task, time};
+
+const SCAN_DURATION: Duration = Duration::from_secs(5);
+/// The longest delay between scans when waiting for the first device at startup.
+const MAX_DISCOVERY_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MQTT_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How many bridge commands may be queued for each device before older ones are dropped.
+const BRIDGE_COMMAND_CAPACITY: usize = 16;
+
+/// Discover thermometers and run each of them, along with the rest of the bridge, until they are all
+/// shut down. If `once` is set then each device stops after publishing one set of readings.
+pub async fn run_system(
+    config: &Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+    session: &BluetoothSession,
+    systemd_notify: bool,
+    once: bool,
+) -> Result<(), Report> {
+    log::info!("Starting discovery");
+    start_discovery(config, session).await?;
+    let mut devices: Vec<DeviceInfo> = vec![];
+    for pass in 1..=config.bbq.discovery_passes.max(1) {
+        time::sleep(SCAN_DURATION).await;
+        // Treat a failed scan like one which found nothing, as the next one may well work.
+        let found = match find_allowed_devices(config, session).await {
+            Ok(found) => found,
+            Err(e) => {
+                log::warn!("Failed to look for devices on pass {}: {}", pass, e);
+                vec![]
+            }
+        };
+        for device in found {
+            if !devices
+                .iter()
+                .any(|found| found.mac_address == device.mac_address)
+            {
+                devices.push(device);
+            }
+        }
+        log::info!("Found {} devices after pass {}", devices.len(), pass);
+    }
+    // There's no point looking for more devices if we're only getting one set of readings.
+    let rediscovery_interval = config
+        .bbq
+        .rediscovery_interval_seconds
+        .filter(|_| !once)
+        .map(Duration::from_secs);
+    // Rediscovery will find devices later anyway, but otherwise wait for at least one to turn up.
+    if devices.is_empty() && rediscovery_interval.is_none() {
+        devices = wait_for_devices(config, session).await?;
+    }
+    if systemd_notify {
+        systemd::notify("READY=1")?;
+    }
+    // Start connecting to higher priority devices first. The sort is stable, so devices with the
+    // same priority keep the order in which they were discovered.
+    devices.sort_by_key(|device| Reverse(config.device_priority(&device.mac_address)));
+
+    let (channels, mut join_handles) = spawn_bridge(config, tls_client_config.clone());
+    let supervisor = Supervisor {
+        session: session.clone(),
+        config: config.to_owned(),
+        tls_client_config,
+        channels,
+        failures: Default::default(),
+        active: Default::default(),
+        once,
+    };
+    // Connect to all devices in parallel, so that one which is slow to connect or fails doesn't
+    // hold up the others.
+    let device_handles: Vec<_> = devices
+        .into_iter()
+        .filter_map(|device| supervisor.spawn(device))
+        .collect();
+    if once {
+        finish_once(device_handles, &supervisor.channels).await?;
+    } else {
+        join_handles.extend(device_handles);
+    }
+    if let Some(rediscovery_interval) = rediscovery_interval {
+        join_handles.push(task::spawn(rediscover(supervisor, rediscovery_interval)));
+    }
+    try_join_all(join_handles).await?;
+
+    Ok(())
+}
+
+/// Scan for thermometers and print the MAC address and name of each one found, without connecting
+/// to them.
+pub async fn list_devices(config: &Config, session: &BluetoothSession) -> Result<(), Report> {
+    println!("Scanning for devices...");
+    start_discovery(config, session).await?;
+    time::sleep(SCAN_DURATION).await;
+    let devices = find_devices(config, session).await?;
+    if devices.is_empty() {
+        println!("No devices found.");
+    }
+    for device in devices {
+        println!(
+            "{} {}",
+            device.mac_address,
+            device.name.as_deref().unwrap_or("(unnamed)")
+        );
+    }
+    Ok(())
+}
+
+/// Keep looking for devices with increasing delays until at least one is found, or until the
+/// configured timeout has passed.
+async fn wait_for_devices(
+    config: &Config,
+    session: &BluetoothSession,
+) -> Result<Vec<DeviceInfo>, Report> {
+    let mut backoff = ExponentialBackoffBuilder::new()
+        .with_initial_interval(SCAN_DURATION)
+        .with_max_interval(MAX_DISCOVERY_RETRY_DELAY)
+        .with_max_elapsed_time(
+            config
+                .bbq
+                .discovery_timeout_seconds
+                .map(Duration::from_secs),
+        )
+        .build();
+    loop {
+        let delay = match backoff.next_backoff() {
+            Some(delay) => delay,
+            None => bail!("No devices found"),
+        };
+        log::info!("No devices found, scanning again in {:?}", delay);
+        time::sleep(delay).await;
+        match find_allowed_devices(config, session).await {
+            Ok(devices) if !devices.is_empty() => {
+                log::info!("Found {} devices", devices.len());
+                return Ok(devices);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to look for devices: {}", e),
+        }
+    }
+}
+
+/// Find the thermometers which have been discovered so far, leaving out any which the config doesn't
+/// allow connecting to.
+async fn find_allowed_devices(
+    config: &Config,
+    session: &BluetoothSession,
+) -> Result<Vec<DeviceInfo>, Report> {
+    let mut devices = find_devices(config, session).await?;
+    devices.retain(|device| {
+        let allowed = config.device_allowed(&device.mac_address);
+        if !allowed {
+            log::debug!(
+                "Ignoring device {} as it is not allowed",
+                device.mac_address
+            );
+        }
+        allowed
+    });
+    Ok(devices)
+}
+
+/// Keep looking for new devices every `interval`, and start supervising any which aren't already
+/// running, until shutdown is requested.
+async fn rediscover(supervisor: Supervisor, interval: Duration) -> Result<(), Report> {
+    let mut shutdown = supervisor.channels.commands.subscribe();
+    let mut join_handles = vec![];
+    loop {
+        select! {
+            () = time::sleep(interval) => {}
+            () = shutdown_requested(&mut shutdown) => break,
+        }
+        // Forget about devices which have finished, so that the list doesn't keep growing.
+        let (finished, running) = join_handles
+            .into_iter()
+            .partition::<Vec<_>, _>(|handle: &task::JoinHandle<_>| handle.is_finished());
+        join_handles = running;
+        try_join_all(finished).await?;
+        // A failed scan may well work next time, so don't let it stop the bridge.
+        let mut devices = match find_allowed_devices(&supervisor.config, &supervisor.session).await
+        {
+            Ok(devices) => devices,
+            Err(e) => {
+                log::warn!("Failed to look for new devices: {}", e);
+                continue;
+            }
+        };
+        devices
+            .sort_by_key(|device| Reverse(supervisor.config.device_priority(&device.mac_address)));
+        for device in devices {
+            let mac_address = device.mac_address;
+            if let Some(handle) = supervisor.spawn(device) {
+                log::info!("Found new device {}", mac_address);
+                join_handles.push(handle);
+            }
+        }
+    }
+    try_join_all(join_handles).await?;
+    Ok(())
+}
+
+/// Everything needed to run devices, shared between all of them.
+#[derive(Clone)]
+struct Supervisor {
+    session: BluetoothSession,
+    config: Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+    channels: BridgeChannels,
+    failures: Arc<Mutex<ConnectionFailures>>,
+    /// The MAC addresses of devices which are currently being supervised.
+    active: Arc<Mutex<HashSet<MacAddress>>>,
+    /// Whether to stop each device after publishing one set of readings.
+    once: bool,
+}
+
+impl Supervisor {
+    /// Spawn a task to supervise the given device, unless it is already being supervised. The
+    /// device is forgotten when the task finishes, so that it can be found again later.
+    fn spawn(&self, device: DeviceInfo) -> Option<task::JoinHandle<Result<(), Report>>> {
+        let mac_address = device.mac_address;
+        if !self.active.lock().unwrap().insert(mac_address) {
+            return None;
+        }
+        let supervisor = self.clone();
+        Some(task::spawn(async move {
+            let result = supervise_device(&supervisor, device).await;
+            supervisor.active.lock().unwrap().remove(&mac_address);
+            result
+        }))
+    }
+}
+
+/// Start discovering devices, only looking for those which advertise the BBQ service if filtering is
+/// enabled.
+async fn start_discovery(config: &Config, session: &BluetoothSession) -> Result<(), Report> {
+    let adapter = find_adapter(config, session).await?;
+    if config.bbq.discovery_filter {
+        let filter = DiscoveryFilter {
+            service_uuids: vec![uuid_from_u16(BBQ_SERVICE_UUID)],
+            ..Default::default()
+        };
+        let result = if let Some(adapter) = &adapter {
+            session
+                .start_discovery_on_adapter_with_filter(adapter, &filter)
+                .await
+        } else {
+            session.start_discovery_with_filter(&filter).await
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => log::warn!("Failed to start filtered discovery, trying without: {}", e),
+        }
+    }
+    if let Some(adapter) = &adapter {
+        session.start_discovery_on_adapter(adapter).await?;
+    } else {
+        session.start_discovery().await?;
+    }
+    Ok(())
+}
+
+/// Find the Bluetooth adapter which the config says to use, or `None` if it says to use them all.
+async fn find_adapter(
+    config: &Config,
+    session: &BluetoothSession,
+) -> Result<Option<AdapterId>, Report> {
+    let name = match &config.bbq.adapter {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let adapters = session.get_adapters().await?;
+    match adapters
+        .into_iter()
+        .find(|adapter| adapter_matches(adapter, name))
+    {
+        Some(adapter) => Ok(Some(adapter.id)),
+        None => bail!("Bluetooth adapter {} not found", name),
+    }
+}
+
+/// Whether the given adapter has the given name or MAC address.
+fn adapter_matches(adapter: &AdapterInfo, name: &str) -> bool {
+    adapter.id.to_string() == name || adapter.mac_address.to_string().eq_ignore_ascii_case(name)
+}
+
+/// Find the thermometers which have been discovered so far by the configured adapter.
+async fn find_devices(
+    config: &Config,
+    session: &BluetoothSession,
+) -> Result<Vec<DeviceInfo>, Report> {
+    let devices = if let Some(adapter) = find_adapter(config, session).await? {
+        session.get_devices_on_adapter(&adapter).await?
+    } else {
+        session.get_devices().await?
+    };
+    Ok(devices
+        .into_iter()
+        .filter(BBQDevice::is_compatible)
+        .collect())
+}
+
+/// Connect to the given device and run it, reconnecting whenever it fails. If it keeps failing then
+/// wait for a growing cooldown before each attempt, to avoid filling the logs with errors for
+/// devices which have been turned off.
+async fn supervise_device(supervisor: &Supervisor, device: DeviceInfo) -> Result<(), Report> {
+    let Supervisor {
+        session,
+        config,
+        tls_client_config,
+        channels,
+        failures,
+        ..
+    } = supervisor;
+    let mac_address = device.mac_address;
+    let mut shutdown = channels.commands.subscribe();
+    loop {
+        let result = match Bbq::connect(session, device.clone(), config.clone()).await {
+            Ok(bbq) => {
+                failures.lock().unwrap().record_success(mac_address);
+                bbq.run(
+                    tls_client_config.clone(),
+                    channels.commands.subscribe(),
+                    channels.readings.clone(),
+                    channels.notifications.clone(),
+                    channels.metrics.clone(),
+                    supervisor.once,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let (count, delay) = {
+                let mut failures = failures.lock().unwrap();
+                let delay = failures.record_failure(mac_address, &config.bbq);
+                (failures.count(mac_address), delay)
+            };
+            if matches!(config.bbq.max_reconnect_attempts, Some(max) if count > max) {
+                log::error!(
+                    "Device {} failed {} times in a row, giving up: {:?}",
+                    mac_address,
+                    count,
+                    e
+                );
+                // Start counting again if the device is rediscovered later.
+                failures.lock().unwrap().record_success(mac_address);
+                return Ok(());
+            }
+            if count < config.bbq.failures_before_cooldown {
+                log::warn!("Device {} failed, retrying: {:?}", mac_address, e);
+            } else {
+                log::warn!(
+                    "Device {} failed {} times in a row, waiting {:?} before retrying: {}",
+                    mac_address,
+                    count,
+                    delay,
+                    e
+                );
+            }
+            select! {
+                () = time::sleep(delay) => {}
+                () = shutdown_requested(&mut shutdown) => return Ok(()),
+            }
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+/// Counts of consecutive connection failures for each device.
+#[derive(Debug, Default)]
+struct ConnectionFailures {
+    counts: HashMap<MacAddress, u32>,
+}
+
+impl ConnectionFailures {
+    /// Record a failure for the given device, and return how long to wait before trying it again.
+    fn record_failure(&mut self, mac_address: MacAddress, config: &BbqConfig) -> Duration {
+        let count = self.counts.entry(mac_address).or_default();
+        *count += 1;
+        reconnect_delay(*count, config)
+    }
+
+    fn record_success(&mut self, mac_address: MacAddress) {
+        self.counts.remove(&mac_address);
+    }
+
+    fn count(&self, mac_address: MacAddress) -> u32 {
+        self.counts.get(&mac_address).copied().unwrap_or_default()
+    }
+}
+
+/// Get the time to wait before reconnecting to a device which has failed the given number of times
+/// in a row. The first few failures are retried quickly, after that the cooldown doubles each time
+/// up to the configured maximum.
+fn reconnect_delay(failures: u32, config: &BbqConfig) -> Duration {
+    if failures < config.failures_before_cooldown {
+        RECONNECT_DELAY
+    } else {
+        let doublings = failures - config.failures_before_cooldown;
+        let cooldown = config
+            .reconnect_cooldown_seconds
+            .saturating_mul(2u64.saturating_pow(doublings));
+        Duration::from_secs(cooldown.min(config.max_reconnect_cooldown_seconds))
+    }
+}
+
+/// Run simulated thermometers as configured, without touching Bluetooth.
+pub async fn run_simulation(
+    config: &Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+    once: bool,
+) -> Result<(), Report> {
+    log::info!("Starting {} simulated devices", config.simulate.devices);
+    let (channels, mut join_handles) = spawn_bridge(config, tls_client_config.clone());
+    let device_handles = (1..=config.simulate.devices).map(|index| {
+        let bbq = Bbq::simulated(index, config.to_owned());
+        task::spawn(bbq.run(
+            tls_client_config.clone(),
+            channels.commands.subscribe(),
+            channels.readings.clone(),
+            channels.notifications.clone(),
+            channels.metrics.clone(),
+            once,
+        ))
+    });
+    if once {
+        finish_once(device_handles.collect(), &channels).await?;
+    } else {
+        join_handles.extend(device_handles);
+    }
+    try_join_all(join_handles).await?;
+
+    Ok(())
+}
+
+/// Wait for every device to publish its readings in `--once` mode, and then shut down the rest of the
+/// bridge.
+async fn finish_once(
+    device_handles: Vec<task::JoinHandle<Result<(), Report>>>,
+    channels: &BridgeChannels,
+) -> Result<(), Report> {
+    try_join_all(device_handles).await?;
+    // It doesn't matter if the bridge device isn't running to receive it.
+    let _ = channels.commands.send(BridgeCommand::Shutdown);
+    Ok(())
+}
+
+/// Create the channels between devices and the rest of the bridge, spawn a task to send shutdown
+/// commands when interrupted, and spawn the bridge device, REST push, notification and metrics tasks
+/// if they are enabled.
+///
+/// Returns the channels for devices to use, and the bridge task if any.
+fn spawn_bridge(
+    config: &Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+) -> (BridgeChannels, Vec<task::JoinHandle<Result<(), Report>>>) {
+    let (commands, _) = broadcast::channel(BRIDGE_COMMAND_CAPACITY);
+    task::spawn(forward_shutdown(commands.clone()));
+    // The push task finishes by itself once all devices have stopped, so don't wait for it.
+    let readings = push_channel(&config.push).map(|(readings, push)| {
+        task::spawn(push);
+        readings
+    });
+    let notifications = notify_channel(&config.notify).map(|(notifications, notify)| {
+        task::spawn(notify);
+        notifications
+    });
+    // Only open a socket if the metrics endpoint is enabled.
+    let metrics = config.metrics.listen_address.map(|listen_address| {
+        let metrics = Arc::new(Metrics::default());
+        let serve = serve_metrics(listen_address, metrics.clone());
+        task::spawn(async move {
+            if let Err(e) = serve.await {
+                log::error!("Metrics endpoint failed: {:?}", e);
+            }
+        });
+        metrics
+    });
+    let mut join_handles = vec![];
+    if config.homie.bridge_device {
+        join_handles.push(task::spawn(run_bridge(
+            config.to_owned(),
+            tls_client_config,
+            commands.clone(),
+        )));
+    }
+    (
+        BridgeChannels {
+            commands,
+            readings,
+            notifications,
+            metrics,
+        },
+        join_handles,
+    )
+}
+
+/// Connect to the configured MQTT broker and publish a test message, without touching Bluetooth.
+pub async fn test_mqtt(
+    config: &Config,
+    tls_client_config: Option<Arc<ClientConfig>>,
+) -> Result<(), Report> {
+    let mqtt_options = get_mqtt_options(&config.mqtt, "test", tls_client_config);
+    let topic = format!(
+        "{}/{}-test",
+        config.homie.prefix, config.homie.device_id_prefix
+    );
+    println!(
+        "Connecting to MQTT broker {}:{}...",
+        config.mqtt.host, config.mqtt.port
+    );
+    let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+    client
+        .publish(&topic, QoS::AtLeastOnce, false, "test")
+        .await?;
+    time::timeout(MQTT_TEST_TIMEOUT, async {
+        loop {
+            match event_loop
+                .poll()
+                .await
+                .wrap_err("Failed to connect to MQTT broker")?
+            {
+                Event::Incoming(Incoming::ConnAck(_)) => println!("Connected."),
+                Event::Incoming(Incoming::PubAck(_)) => {
+                    println!("Published test message to {}.", topic);
+                    return Ok::<_, Report>(());
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .wrap_err("Timed out waiting for MQTT broker")??;
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_cooldown() {
+        let config = BbqConfig {
+            failures_before_cooldown: 3,
+            reconnect_cooldown_seconds: 60,
+            max_reconnect_cooldown_seconds: 300,
+            ..Default::default()
+        };
+        assert_eq!(reconnect_delay(1, &config), RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(2, &config), RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(3, &config), Duration::from_secs(60));
+        assert_eq!(reconnect_delay(4, &config), Duration::from_secs(120));
+        assert_eq!(reconnect_delay(5, &config), Duration::from_secs(240));
+        assert_eq!(reconnect_delay(6, &config), Duration::from_secs(300));
+        assert_eq!(reconnect_delay(100, &config), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Tells systemd when the bridge is ready and that it is still healthy, for services with
+//! `Type=notify` and `WatchdogSec` set.
+
+use bluez_async::BluetoothSession;
+use eyre::{Report, WrapErr};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+use tokio::time::{self, timeout};
+
+/// How long to wait for D-Bus to respond before treating it as hung and skipping a watchdog ping.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send the given state, such as `READY=1`, to systemd. Does nothing if the service manager didn't
+/// give us a socket to notify it on.
+pub fn notify(state: &str) -> Result<(), Report> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => {
+            log::debug!("NOTIFY_SOCKET not set, not sending {}", state);
+            return Ok(());
+        }
+    };
+    let address = if let Some(name) = path.strip_prefix('@') {
+        SocketAddr::from_abstract_name(name)?
+    } else {
+        SocketAddr::from_pathname(&path)?
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(state.as_bytes(), &address)
+        .wrap_err_with(|| format!("Failed to notify systemd on {}", path))?;
+    Ok(())
+}
+
+/// Keep pinging the systemd watchdog, as long as D-Bus is still responding. If the watchdog isn't
+/// enabled for this process then this just waits forever.
+pub async fn run_watchdog(session: BluetoothSession) -> Result<(), Report> {
+    let interval = match watchdog_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    ) {
+        Some(interval) => interval,
+        None => {
+            log::info!("systemd watchdog not enabled");
+            return std::future::pending().await;
+        }
+    };
+    log::info!("Pinging systemd watchdog every {:?}", interval);
+    loop {
+        time::sleep(interval).await;
+        match timeout(HEALTH_CHECK_TIMEOUT, session.get_adapters()).await {
+            Ok(Ok(_)) => notify("WATCHDOG=1")?,
+            Ok(Err(e)) => log::warn!("D-Bus health check failed, not pinging watchdog: {}", e),
+            Err(_) => log::warn!("D-Bus health check timed out, not pinging watchdog"),
+        }
+    }
+}
+
+/// How often to ping the watchdog, given the `WATCHDOG_USEC` and `WATCHDOG_PID` environment
+/// variables set by systemd and our own PID. This is half the watchdog timeout, so that a ping
+/// being a little late doesn't trigger it.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_intervals() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        // The watchdog is for another process.
+        assert_eq!(watchdog_interval(Some("30000000"), Some("41"), 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(Some("invalid"), None, 42), None);
+    }
+}
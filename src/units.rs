@@ -0,0 +1,81 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+use cloudbbq::TemperatureUnit;
+
+/// Convert an absolute temperature from the given unit to Celsius.
+pub fn to_celsius(temperature: f32, unit: TemperatureUnit) -> f32 {
+    match unit {
+        TemperatureUnit::Celcius => temperature,
+        TemperatureUnit::Fahrenheit => (temperature - 32.0) * 5.0 / 9.0,
+    }
+}
+
+/// Convert an absolute temperature from Celsius to the given unit.
+pub fn from_celsius(temperature: f32, unit: TemperatureUnit) -> f32 {
+    match unit {
+        TemperatureUnit::Celcius => temperature,
+        TemperatureUnit::Fahrenheit => temperature * 9.0 / 5.0 + 32.0,
+    }
+}
+
+/// Convert a temperature difference, such as an offset, from the given unit to Celsius.
+pub fn difference_to_celsius(difference: f32, unit: TemperatureUnit) -> f32 {
+    match unit {
+        TemperatureUnit::Celcius => difference,
+        TemperatureUnit::Fahrenheit => difference * 5.0 / 9.0,
+    }
+}
+
+/// Convert a temperature difference, such as a rate of change, from Celsius to the given unit.
+pub fn difference_from_celsius(difference: f32, unit: TemperatureUnit) -> f32 {
+    match unit {
+        TemperatureUnit::Celcius => difference,
+        TemperatureUnit::Fahrenheit => difference * 9.0 / 5.0,
+    }
+}
+
+/// Parse a temperature unit given as "C" or "F", or the full "ºC" or "ºF".
+pub fn parse_temperature_unit(unit: &str) -> Option<TemperatureUnit> {
+    match unit {
+        "C" | "ºC" => Some(TemperatureUnit::Celcius),
+        "F" | "ºF" => Some(TemperatureUnit::Fahrenheit),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fahrenheit_to_celsius() {
+        assert_eq!(to_celsius(212.0, TemperatureUnit::Fahrenheit), 100.0);
+        assert_eq!(to_celsius(-40.0, TemperatureUnit::Fahrenheit), -40.0);
+        assert_eq!(difference_to_celsius(9.0, TemperatureUnit::Fahrenheit), 5.0);
+    }
+
+    #[test]
+    fn celsius_to_fahrenheit() {
+        assert_eq!(from_celsius(100.0, TemperatureUnit::Fahrenheit), 212.0);
+        assert_eq!(from_celsius(-40.0, TemperatureUnit::Fahrenheit), -40.0);
+        assert_eq!(from_celsius(-20.0, TemperatureUnit::Fahrenheit), -4.0);
+        assert_eq!(from_celsius(21.5, TemperatureUnit::Celcius), 21.5);
+        assert_eq!(
+            difference_from_celsius(5.0, TemperatureUnit::Fahrenheit),
+            9.0
+        );
+    }
+
+    #[test]
+    fn below_zero_fahrenheit() {
+        assert_eq!(to_celsius(32.0, TemperatureUnit::Fahrenheit), 0.0);
+        assert_eq!(to_celsius(-4.0, TemperatureUnit::Fahrenheit), -20.0);
+        assert_eq!(to_celsius(-58.0, TemperatureUnit::Fahrenheit), -50.0);
+        assert_eq!(
+            difference_to_celsius(-9.0, TemperatureUnit::Fahrenheit),
+            -5.0
+        );
+    }
+}
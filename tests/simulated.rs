@@ -0,0 +1,98 @@
+// Copyright 2021 the cloudbbq-homie authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Runs a simulated thermometer using only the public API of the library, as another program
+//! embedding the bridge would.
+
+use cloudbbq_homie::{Bbq, BbqConfig, BridgeCommand, Config, Metrics};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+
+/// A minimal MQTT 3.1.1 broker which accepts a single client, acknowledges everything it sends,
+/// and returns the topics which it published to once it disconnects.
+async fn fake_broker(listener: TcpListener) -> Vec<String> {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut topics = vec![];
+    loop {
+        let mut header = [0; 1];
+        if stream.read_exact(&mut header).await.is_err() {
+            return topics;
+        }
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            length |= usize::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        let response = match header[0] >> 4 {
+            // CONNECT
+            1 => vec![0x20, 2, 0, 0],
+            // PUBLISH
+            3 => {
+                let topic_length = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                topics.push(String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap());
+                if header[0] & 0x06 == 0 {
+                    continue;
+                }
+                vec![0x40, 2, body[2 + topic_length], body[3 + topic_length]]
+            }
+            // SUBSCRIBE, granting QoS 1 for the single filter which homie-device sends at a time.
+            8 => vec![0x90, 3, body[0], body[1], 1],
+            // PINGREQ
+            12 => vec![0xd0, 0],
+            // DISCONNECT
+            14 => return topics,
+            _ => continue,
+        };
+        stream.write_all(&response).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn run_simulated_device() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let bbq_config = BbqConfig {
+        restore_display_unit: false,
+        ..Default::default()
+    };
+    let config = Config::builder()
+        .mqtt_host("127.0.0.1")
+        .mqtt_port(port)
+        .bbq(bbq_config)
+        .build()
+        .unwrap();
+    let broker = tokio::spawn(fake_broker(listener));
+    let (_bridge_commands_tx, bridge_commands) = broadcast::channel::<BridgeCommand>(1);
+    let (readings_tx, mut readings) = mpsc::channel(100);
+    let (notifications_tx, _notifications) = mpsc::channel(100);
+    let metrics = Arc::new(Metrics::default());
+
+    Bbq::simulated(1, config)
+        .run(
+            None,
+            bridge_commands,
+            Some(readings_tx),
+            Some(notifications_tx),
+            Some(metrics),
+            true,
+        )
+        .await
+        .unwrap();
+
+    let topics = broker.await.unwrap();
+    assert!(topics
+        .iter()
+        .any(|topic| topic == "homie/cloudbbq-020000000001/probe0/temperature"));
+    let reading = readings.recv().await.unwrap();
+    assert_eq!(reading.device, "02:00:00:00:00:01");
+}